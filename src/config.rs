@@ -0,0 +1,590 @@
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::process;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::forwarder::{ForwardStrategy, ZoneMode};
+use crate::logger::LogLevel;
+use crate::tsig::Key;
+
+const USAGE: &str = "\
+Usage: dns_server [OPTIONS]
+
+Options:
+  -c, --config <FILE>          TOML config file, flags override its settings
+  -l, --listen <ADDR>          Address to serve UDP and TCP on, repeat for more
+      --root-hints <FILE>      Root hints file [default: assets/named.root.txt]
+      --upstream-port <PORT>   Port upstream nameservers listen on [default: 53]
+      --timeout <MS>           Upstream query timeout in milliseconds [default: 2000]
+      --retries <N>            Retries per upstream server on timeout [default: 1]
+      --recursion-limit <N>    Referrals followed per name [default: 100]
+      --max-outstanding <N>    Recursions allowed at once [default: 100]
+      --cache-size <N>         Cached RRsets, 0 disables the cache [default: 10000]
+      --workers <N>            UDP worker threads per address [default: 128]
+      --tcp-connections <N>    TCP connections served at once [default: 64]
+      --log-level <LEVEL>      off, error, warn, info or debug [default: info]
+      --upstream <ADDR>        Resolver to forward to, repeat for more
+      --forward-strategy <S>   round-robin or lowest-latency [default: round-robin]
+      --health-check <MS>      Interval between upstream health checks [default: 10000]
+      --forward-zone <Z=ADDRS> Forward names in zone Z to comma separated resolvers
+      --stub-zone <Z=ADDRS>    Resolve names in zone Z starting at its own nameservers
+      --zone <Z=FILE>          Serve zone Z authoritatively from a master file
+      --secondary <Z=ADDR>     Serve zone Z as a secondary of the primary at ADDR
+      --enable <MODE>          Turn on a server mode: recursive, forward, chaos
+      --disable <MODE>         Turn off a server mode
+  -h, --help                   Print this help";
+
+#[derive(Debug)]
+pub struct ConfigError(String);
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub listen:          Vec<SocketAddr>,
+    pub log_level:       LogLevel,
+    pub workers:         usize,
+    pub tcp_connections: usize,
+    pub modes:           ModesSection,
+    pub resolver:        ResolverSection,
+    pub forwarder:       ForwarderSection,
+    pub forward_zones:   Vec<ForwardZoneSection>,
+    pub zones:           Vec<ZoneSection>,
+    pub keys:            Vec<Key>,
+    pub cache:           CacheSection,
+}
+
+// Which kinds of questions the server answers, anything else is refused
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModesSection {
+    pub recursive: bool,
+    pub forward:   bool, // Takes over from recursive when both are on
+    pub chaos:     bool,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ResolverSection {
+    pub root_hints:      PathBuf,
+    pub upstream_port:   u16,
+    pub timeout_ms:      u64,
+    pub retries:         u32,
+    pub recursion_limit: u32,
+    pub max_outstanding: usize,
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwarderSection {
+    pub upstreams:       Vec<SocketAddr>,
+    pub strategy:        ForwardStrategy,
+    pub health_check_ms: u64,
+}
+
+// Names at or below `name` go to `servers` instead of the usual resolution, the
+// longest matching name wins
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ForwardZoneSection {
+    pub name:    String,
+    pub servers: Vec<SocketAddr>,
+    #[serde(default = "default_zone_mode")]
+    pub mode:    ZoneMode,
+}
+
+// A zone answered authoritatively, either as the primary from the master file at
+// `file` or as a secondary transferring it from `primary`
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ZoneSection {
+    pub name:           String,
+    pub file:           Option<PathBuf>,
+    pub primary:        Option<SocketAddr>,
+    pub journal:        Option<PathBuf>, // Where a primary keeps changes for IXFR, <file>.jnl by default
+    #[serde(default)]
+    pub allow_transfer: Vec<IpAddr>,     // Clients that may AXFR or IXFR the zone, nobody by default
+    #[serde(default)]
+    pub notify:         Vec<SocketAddr>, // Secondaries sent a NOTIFY when the serial changes
+    #[serde(default)]
+    pub allow_update:   Vec<IpAddr>,     // Clients that may send dynamic updates, nobody by default
+    pub key:            Option<String>,  // TSIG key that also grants transfers, updates and NOTIFYs
+}
+
+impl ZoneSection {
+    pub fn journal_path(&self) -> Option<PathBuf> {
+        if self.journal.is_some() {
+            return self.journal.clone();
+        }
+
+        return self.file.as_ref().map(|file| {
+            let mut path = file.clone().into_os_string();
+            path.push(".jnl");
+            PathBuf::from(path)
+        });
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheSection {
+    pub max_entries: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            listen:          vec![SocketAddr::from(([0, 0, 0, 0], 8888))],
+            log_level:       LogLevel::Info,
+            workers:         128,
+            tcp_connections: 64,
+            modes:           ModesSection::default(),
+            resolver:        ResolverSection::default(),
+            forwarder:       ForwarderSection::default(),
+            forward_zones:   Vec::new(),
+            zones:           Vec::new(),
+            keys:            Vec::new(),
+            cache:           CacheSection::default(),
+        }
+    }
+}
+
+impl Default for ModesSection {
+    fn default() -> Self {
+        Self {
+            recursive: true,
+            forward:   false,
+            chaos:     true,
+        }
+    }
+}
+
+impl Default for ResolverSection {
+    fn default() -> Self {
+        Self {
+            root_hints:      PathBuf::from("assets/named.root.txt"),
+            upstream_port:   53,
+            timeout_ms:      2000,
+            retries:         1,
+            recursion_limit: 100,
+            max_outstanding: 100,
+        }
+    }
+}
+
+impl Default for ForwarderSection {
+    fn default() -> Self {
+        Self {
+            upstreams:       Vec::new(),
+            strategy:        ForwardStrategy::RoundRobin,
+            health_check_ms: 10000,
+        }
+    }
+}
+
+impl Default for CacheSection {
+    fn default() -> Self {
+        Self {
+            max_entries: 10000,
+        }
+    }
+}
+
+impl Config {
+    // Builds the config from the command line: the file named by --config if there is
+    // one, then every other flag on top of it
+    pub fn from_args(args: &[String]) -> Result<Self, ConfigError> {
+        let flags      = parse_flags(args)?;
+        let mut config = match config_path(&flags) {
+            Some(path) => Self::from_file(&path)?,
+            None       => Self::default(),
+        };
+
+        let mut listen    = Vec::new();
+        let mut upstreams = Vec::new();
+        let mut zones     = Vec::new();
+        let mut auth      = Vec::new();
+        for (flag, value) in flags {
+            match flag {
+                "-c" | "--config"    => (),
+                "-l" | "--listen"    => listen.push(parse_value(flag, value)?),
+                "--root-hints"       => config.resolver.root_hints = PathBuf::from(value),
+                "--upstream-port"    => config.resolver.upstream_port = parse_value(flag, value)?,
+                "--timeout"          => config.resolver.timeout_ms = parse_value(flag, value)?,
+                "--retries"          => config.resolver.retries = parse_value(flag, value)?,
+                "--recursion-limit"  => config.resolver.recursion_limit = parse_value(flag, value)?,
+                "--max-outstanding"  => config.resolver.max_outstanding = parse_value(flag, value)?,
+                "--upstream"         => upstreams.push(parse_value(flag, value)?),
+                "--forward-strategy" => config.forwarder.strategy = parse_value(flag, value)?,
+                "--health-check"     => config.forwarder.health_check_ms = parse_value(flag, value)?,
+                "--forward-zone"     => zones.push(parse_zone(flag, value, ZoneMode::Forward)?),
+                "--stub-zone"        => zones.push(parse_zone(flag, value, ZoneMode::Stub)?),
+                "--zone"             => auth.push(parse_auth_zone(flag, value, false)?),
+                "--secondary"        => auth.push(parse_auth_zone(flag, value, true)?),
+                "--cache-size"       => config.cache.max_entries = parse_value(flag, value)?,
+                "--workers"          => config.workers = parse_value(flag, value)?,
+                "--tcp-connections"  => config.tcp_connections = parse_value(flag, value)?,
+                "--log-level"        => config.log_level = parse_value(flag, value)?,
+                "--enable"           => *config.mode(value)? = true,
+                "--disable"          => *config.mode(value)? = false,
+                _                    => return Err(ConfigError(format!("unknown option {}, see --help", flag))),
+            }
+        }
+
+        // Lists given on the command line replace the file's, not add to them
+        if !listen.is_empty() {
+            config.listen = listen;
+        }
+
+        if !upstreams.is_empty() {
+            config.forwarder.upstreams = upstreams;
+        }
+
+        if !zones.is_empty() {
+            config.forward_zones = zones;
+        }
+
+        if !auth.is_empty() {
+            config.zones = auth;
+        }
+
+        for zone in &mut config.forward_zones {
+            zone.name = normalize_zone_name(&zone.name);
+        }
+
+        for zone in &mut config.zones {
+            zone.name = normalize_zone_name(&zone.name);
+            zone.key  = zone.key.as_deref().map(normalize_zone_name);
+        }
+
+        for key in &mut config.keys {
+            key.name = normalize_zone_name(&key.name);
+        }
+
+        config.validate()?;
+        return Ok(config);
+    }
+
+    pub fn key(&self, name: &str) -> Option<&Key> {
+        return self.keys.iter().find(|key| key.name == name);
+    }
+
+    pub fn zone_key(&self, zone: &ZoneSection) -> Option<&Key> {
+        return zone.key.as_deref().and_then(|name| self.key(name));
+    }
+
+    // Forwarding takes every IN question, leaving nothing for recursion from the roots
+    pub fn uses_root_hints(&self) -> bool {
+        return self.modes.recursive && !self.modes.forward;
+    }
+
+    fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let text = fs::read_to_string(path)
+                      .map_err(|err| ConfigError(format!("could not read config file {}: {}", path, err)))?;
+
+        return toml::from_str(&text)
+                   .map_err(|err| ConfigError(format!("invalid config file {}: {}", path, err)));
+    }
+
+    fn mode(&mut self, name: &str) -> Result<&mut bool, ConfigError> {
+        match name {
+            "recursive" => Ok(&mut self.modes.recursive),
+            "forward"   => Ok(&mut self.modes.forward),
+            "chaos"     => Ok(&mut self.modes.chaos),
+            _           => Err(ConfigError(format!("unknown mode {}, expected recursive, forward or chaos", name))),
+        }
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let fail = |message: &str| Err(ConfigError(message.to_string()));
+
+        if self.listen.is_empty() {
+            return fail("listen needs at least one address");
+        }
+
+        if self.workers == 0 {
+            return fail("workers must be at least 1");
+        }
+
+        if self.tcp_connections == 0 {
+            return fail("tcp_connections must be at least 1");
+        }
+
+        if !self.modes.recursive && !self.modes.forward && !self.modes.chaos && self.zones.is_empty() {
+            return fail("every server mode is disabled and no zones are configured, enable at least one");
+        }
+
+        if self.resolver.upstream_port == 0 {
+            return fail("resolver.upstream_port can't be 0");
+        }
+
+        if self.resolver.timeout_ms == 0 {
+            return fail("resolver.timeout_ms must be at least 1");
+        }
+
+        if self.resolver.recursion_limit == 0 {
+            return fail("resolver.recursion_limit must be at least 1");
+        }
+
+        if self.resolver.max_outstanding == 0 {
+            return fail("resolver.max_outstanding must be at least 1");
+        }
+
+        // Workers waiting on upstream servers can't answer anything else, some have to
+        // be left for answers from the cache and zones
+        if (self.modes.recursive || self.modes.forward) && self.workers <= self.resolver.max_outstanding {
+            return fail("workers must be more than resolver.max_outstanding");
+        }
+
+        if self.modes.forward && self.forwarder.upstreams.is_empty() {
+            return fail("forward mode needs at least one address in forwarder.upstreams");
+        }
+
+        if self.modes.forward && self.forwarder.upstreams.iter().any(|upstream| upstream.port() == 0) {
+            return fail("forwarder.upstreams need a port other than 0");
+        }
+
+        if self.forwarder.health_check_ms == 0 {
+            return fail("forwarder.health_check_ms must be at least 1");
+        }
+
+        if !self.forward_zones.is_empty() && !self.modes.recursive && !self.modes.forward {
+            return fail("forward_zones need the recursive or forward mode");
+        }
+
+        for (index, zone) in self.forward_zones.iter().enumerate() {
+            let name = match zone.name.as_str() {
+                ""   => ".",
+                name => name,
+            };
+
+            if zone.servers.is_empty() || zone.servers.iter().any(|server| server.port() == 0) {
+                return Err(ConfigError(format!("forward_zones: {} needs servers with a port other than 0", name)));
+            }
+
+            if self.forward_zones[..index].iter().any(|other| other.name == zone.name) {
+                return Err(ConfigError(format!("forward_zones: {} is listed more than once", name)));
+            }
+        }
+
+        for (index, zone) in self.zones.iter().enumerate() {
+            if zone.file.is_some() == zone.primary.is_some() {
+                return Err(ConfigError(format!("zones: {}. needs either a file or a primary", zone.name)));
+            }
+
+            if zone.primary.is_some_and(|primary| primary.port() == 0) {
+                return Err(ConfigError(format!("zones: {}. needs a primary with a port other than 0", zone.name)));
+            }
+
+            if zone.notify.iter().any(|secondary| secondary.port() == 0) {
+                return Err(ConfigError(format!("zones: {}. needs notify addresses with a port other than 0", zone.name)));
+            }
+
+            if zone.journal.is_some() && zone.file.is_none() {
+                return Err(ConfigError(format!("zones: {}. only keeps a journal as the primary", zone.name)));
+            }
+
+            if !zone.allow_update.is_empty() && zone.file.is_none() {
+                return Err(ConfigError(format!("zones: {}. only takes updates as the primary", zone.name)));
+            }
+
+            if zone.key.as_ref().is_some_and(|name| self.key(name).is_none()) {
+                return Err(ConfigError(format!("zones: {}. uses a key that isn't in keys", zone.name)));
+            }
+
+            if self.zones[..index].iter().any(|other| other.name == zone.name) {
+                return Err(ConfigError(format!("zones: {}. is listed more than once", zone.name)));
+            }
+        }
+
+        for (index, key) in self.keys.iter().enumerate() {
+            if key.secret.is_empty() {
+                return Err(ConfigError(format!("keys: {}. needs a secret", key.name)));
+            }
+
+            if self.keys[..index].iter().any(|other| other.name == key.name) {
+                return Err(ConfigError(format!("keys: {}. is listed more than once", key.name)));
+            }
+        }
+
+        if self.uses_root_hints() && !self.resolver.root_hints.is_file() {
+            return Err(ConfigError(format!("resolver.root_hints: {} is not a readable file", self.resolver.root_hints.display())));
+        }
+
+        return Ok(());
+    }
+}
+
+// Pairs every flag with the value after it. -h and --help are the only flags without
+// one, and print the usage right away wherever they are.
+fn parse_flags(args: &[String]) -> Result<Vec<(&str, &str)>, ConfigError> {
+    let mut flags = Vec::new();
+    let mut args  = args.iter();
+
+    while let Some(flag) = args.next() {
+        if flag == "-h" || flag == "--help" {
+            println!("{}", USAGE);
+            process::exit(0);
+        }
+
+        let Some(value) = args.next() else {
+            return Err(ConfigError(format!("{} expects a value, see --help", flag)));
+        };
+
+        flags.push((flag.as_str(), value.as_str()));
+    }
+
+    return Ok(flags);
+}
+
+fn config_path(flags: &[(&str, &str)]) -> Option<String> {
+    return flags.iter()
+                .rev()
+                .find(|(flag, _)| *flag == "-c" || *flag == "--config")
+                .map(|(_, path)| path.to_string());
+}
+
+// Zone names are compared lowercase and without the trailing dot, the root being ""
+fn normalize_zone_name(name: &str) -> String {
+    return name.trim_end_matches('.').to_lowercase();
+}
+
+fn default_zone_mode() -> ZoneMode {
+    return ZoneMode::Forward;
+}
+
+// Parses "corp.example=10.0.0.53:53,10.0.0.54:53"
+fn parse_zone(flag: &str, value: &str, mode: ZoneMode) -> Result<ForwardZoneSection, ConfigError> {
+    let Some((name, servers)) = value.split_once('=') else {
+        return Err(ConfigError(format!("invalid value {:?} for {}: expected ZONE=ADDR[,ADDR...]", value, flag)));
+    };
+
+    let servers = servers.split(',')
+                         .map(|server| parse_value(flag, server))
+                         .collect::<Result<_, _>>()?;
+
+    return Ok(ForwardZoneSection {
+        name:    name.to_string(),
+        servers: servers,
+        mode:    mode,
+    });
+}
+
+// Parses "example.com=zones/example.com.zone", or "example.com=192.0.2.1:53" for
+// a secondary
+fn parse_auth_zone(flag: &str, value: &str, secondary: bool) -> Result<ZoneSection, ConfigError> {
+    let Some((name, source)) = value.split_once('=') else {
+        let expected = if secondary { "ZONE=ADDR" } else { "ZONE=FILE" };
+        return Err(ConfigError(format!("invalid value {:?} for {}: expected {}", value, flag, expected)));
+    };
+
+    let (file, primary) = match secondary {
+        true  => (None, Some(parse_value(flag, source)?)),
+        false => (Some(PathBuf::from(source)), None),
+    };
+
+    return Ok(ZoneSection {
+        name:           name.to_string(),
+        file:           file,
+        primary:        primary,
+        journal:        None,
+        allow_transfer: Vec::new(),
+        notify:         Vec::new(),
+        allow_update:   Vec::new(),
+        key:            None,
+    });
+}
+
+fn parse_value<T>(flag: &str, value: &str) -> Result<T, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    return value.parse()
+                .map_err(|err| ConfigError(format!("invalid value {:?} for {}: {}", value, flag, err)));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_args(args: &[&str]) -> Result<Config, String> {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        return Config::from_args(&args).map_err(|err| err.to_string());
+    }
+
+    fn error(args: &[&str]) -> String {
+        return from_args(args).err().expect("config should be refused");
+    }
+
+    // Writes the config file to the temp directory, named after the test
+    fn config_file(test: &str, text: &str) -> String {
+        let path = std::env::temp_dir().join(format!("config_{}_{}.toml", test, std::process::id()));
+        fs::write(&path, text).unwrap();
+        return path.to_string_lossy().to_string();
+    }
+
+    #[test]
+    fn flags_override_the_file() {
+        let path = config_file("override", "listen = [\"127.0.0.1:5300\"]\n\
+                                            workers = 300\n\
+                                            [resolver]\n\
+                                            timeout_ms = 500\n");
+
+        let config = from_args(&["--workers", "400", "-c", &path, "-l", "127.0.0.1:53", "-l", "[::1]:53"]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.workers, 400);
+        assert_eq!(config.resolver.timeout_ms, 500);
+        assert_eq!(config.resolver.retries, 1);
+        assert_eq!(config.listen, ["127.0.0.1:53".parse().unwrap(), "[::1]:53".parse::<SocketAddr>().unwrap()]);
+    }
+
+    #[test]
+    fn flags_need_values() {
+        assert_eq!(error(&["--workers"]), "--workers expects a value, see --help");
+        assert_eq!(error(&["--verbose", "1"]), "unknown option --verbose, see --help");
+        assert_eq!(error(&["--workers", "many"]), "invalid value \"many\" for --workers: invalid digit found in string");
+        assert_eq!(error(&["--enable", "dnssec"]), "unknown mode dnssec, expected recursive, forward or chaos");
+    }
+
+    #[test]
+    fn unknown_fields_are_refused() {
+        let path  = config_file("unknown", "[resolver]\ntimeout = 500\n");
+        let error = error(&["--config", &path]);
+        fs::remove_file(&path).unwrap();
+
+        assert!(error.starts_with(&format!("invalid config file {}", path)), "{}", error);
+        assert!(error.contains("unknown field `timeout`"), "{}", error);
+    }
+
+    #[test]
+    fn settings_are_validated() {
+        assert!(from_args(&[]).is_ok());
+
+        assert_eq!(error(&["--workers", "100"]), "workers must be more than resolver.max_outstanding");
+        assert_eq!(error(&["--max-outstanding", "200"]), "workers must be more than resolver.max_outstanding");
+        assert!(from_args(&["--workers", "100", "--disable", "recursive"]).is_ok());
+
+        assert_eq!(error(&["--upstream-port", "0"]), "resolver.upstream_port can't be 0");
+        assert_eq!(error(&["--upstream-port", "65536"]),
+                   "invalid value \"65536\" for --upstream-port: number too large to fit in target type");
+        assert_eq!(error(&["--listen", "127.0.0.1"]),
+                   "invalid value \"127.0.0.1\" for --listen: invalid socket address syntax");
+
+        assert_eq!(error(&["--root-hints", "assets/missing.txt"]),
+                   "resolver.root_hints: assets/missing.txt is not a readable file");
+        assert!(from_args(&["--root-hints", "assets/missing.txt", "--disable", "recursive"]).is_ok());
+    }
+}
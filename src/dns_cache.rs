@@ -0,0 +1,575 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::dns_class::DnsClass;
+use crate::dns_packet::DnsPacket;
+use crate::dns_query_type::QueryType;
+use crate::dns_record::DnsRecord;
+use crate::dns_result_code::ResultCode;
+
+struct CacheEntry {
+    records:  Vec<DnsRecord>,
+    inserted: Instant,
+    ttl:      u32,
+    expiry:   Expiry,
+}
+
+// A cached NXDOMAIN or NODATA answer, along with the SOA that proves it
+struct NegativeEntry {
+    soa:      DnsRecord,
+    inserted: Instant,
+    ttl:      u32,
+    expiry:   Expiry,
+}
+
+// Which map an entry is in, for finding it from the expiry index
+#[derive(Clone)]
+enum EntryKey {
+    Answer((String, QueryType, DnsClass)),
+    NoData((String, QueryType, DnsClass)),
+    NxDomain((String, DnsClass)),
+}
+
+// When an entry expires, along with a number telling apart entries that expire at
+// the same moment
+type Expiry = (Instant, u64);
+
+pub struct DnsCache {
+    max_entries: usize, // Positive and negative entries together, 0 caches nothing
+    entries:     HashMap<(String, QueryType, DnsClass), CacheEntry>,
+    nodata:      HashMap<(String, QueryType, DnsClass), NegativeEntry>,
+    nxdomain:    HashMap<(String, DnsClass), NegativeEntry>,
+    expiry:      BTreeMap<Expiry, EntryKey>, // Every entry, soonest to expire first
+    inserts:     u64,
+}
+
+impl DnsCache {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            max_entries: max_entries,
+            entries:     HashMap::new(),
+            nodata:      HashMap::new(),
+            nxdomain:    HashMap::new(),
+            expiry:      BTreeMap::new(),
+            inserts:     0,
+        }
+    }
+
+    // Returns the cached RRset with its TTLs reduced by the time spent in the cache
+    pub fn lookup(&mut self, qname: &str, qtype: QueryType, qclass: DnsClass) -> Option<Vec<DnsRecord>> {
+        let key     = (qname.to_lowercase(), qtype, qclass);
+        let entry   = self.entries.get(&key)?;
+        let elapsed = entry.inserted.elapsed().as_secs();
+
+        if elapsed >= entry.ttl as u64 {
+            self.remove(&EntryKey::Answer(key));
+            return None;
+        }
+
+        let remaining = entry.ttl - elapsed as u32;
+        let records   = entry.records
+                             .iter()
+                             .cloned()
+                             .map(|mut record| {
+                                 record.set_ttl(remaining);
+                                 record
+                             })
+                             .collect();
+
+        return Some(records);
+    }
+
+    // Returns the response code and SOA of a cached negative answer (RFC 2308). NXDOMAIN
+    // covers every type at the name, NODATA only the type that was asked for.
+    pub fn lookup_negative(&mut self, qname: &str, qtype: QueryType, qclass: DnsClass) -> Option<(ResultCode, DnsRecord)> {
+        let qname = qname.to_lowercase();
+
+        if let Some(soa) = self.take_unexpired(EntryKey::NxDomain((qname.clone(), qclass))) {
+            return Some((ResultCode::NXDOMAIN, soa));
+        }
+
+        if let Some(soa) = self.take_unexpired(EntryKey::NoData((qname, qtype, qclass))) {
+            return Some((ResultCode::NOERROR, soa));
+        }
+
+        return None;
+    }
+
+    // Groups the records into RRsets and replaces whatever was cached for each of them
+    pub fn store(&mut self, records: &[DnsRecord]) {
+        let mut rrsets: HashMap<(String, QueryType, DnsClass), Vec<DnsRecord>> = HashMap::new();
+        for record in records {
+            rrsets.entry((record.get_domain().to_lowercase(), record.get_qtype(), record.get_class()))
+                  .or_default()
+                  .push(record.clone());
+        }
+
+        for (key, mut records) in rrsets {
+            // An RRset lives only as long as its shortest TTL
+            let ttl = records.iter().map(|record| record.get_ttl()).min().unwrap_or(0);
+            if ttl == 0 {
+                continue;
+            }
+
+            if !self.entries.contains_key(&key) && !self.make_room() {
+                continue;
+            }
+
+            for record in &mut records {
+                record.set_ttl(ttl);
+            }
+
+            let inserted = Instant::now();
+            let entry    = CacheEntry {
+                records:  records,
+                inserted: inserted,
+                ttl:      ttl,
+                expiry:   self.schedule(EntryKey::Answer(key.clone()), inserted, ttl),
+            };
+
+            if let Some(replaced) = self.entries.insert(key, entry) {
+                self.expiry.remove(&replaced.expiry);
+            }
+        }
+    }
+
+    // Caches the answers of an upstream response along with any delegation it carries.
+    // `zone_cut` is the zone the queried server was asked as an authority for, and
+    // nothing outside of it is trusted: answers only for the question and its CNAME
+    // chain, NS records only for zones below the cut that enclose the question, and
+    // glue only for hosts named by those NS records.
+    pub fn store_packet(&mut self, packet: &DnsPacket, zone_cut: &str) {
+        let question = match packet.question_section.first() {
+            Some(question) => question,
+            None           => return,
+        };
+
+        let qname = question.qname.to_lowercase();
+        self.store(&in_bailiwick(packet, zone_cut));
+        self.store_negative(packet, &qname, question.qtype, question.qclass, zone_cut);
+
+        let mut referral = Vec::new();
+        for record in &packet.authority_section {
+            if matches!(record, DnsRecord::NS { domain, .. } if is_delegation(&qname, domain, zone_cut)) {
+                referral.push(record.clone());
+            }
+        }
+
+        let mut glue = Vec::new();
+        for record in &packet.additional_section {
+            let host = match record {
+                DnsRecord::A { domain, .. } | DnsRecord::AAAA { domain, .. } => domain,
+                _                                                            => continue,
+            };
+
+            let is_glue = referral.iter().any(|ns| {
+                matches!(ns, DnsRecord::NS { host: ns_host, .. } if ns_host.eq_ignore_ascii_case(host))
+            });
+
+            if is_glue && is_subdomain(&host.to_lowercase(), zone_cut) {
+                glue.push(record.clone());
+            }
+        }
+
+        self.store(&referral);
+        self.store(&glue);
+    }
+
+    // NXDOMAIN, or NOERROR with no answers, is only cacheable when the authority section
+    // carries the SOA of an enclosing zone. Its lifetime is the lesser of the SOA's TTL
+    // and its MINIMUM field.
+    fn store_negative(&mut self, packet: &DnsPacket, qname: &str, qtype: QueryType, qclass: DnsClass, zone_cut: &str) {
+        if !packet.answer_section.is_empty() {
+            return;
+        }
+
+        let soa = packet.authority_section.iter().find(|record| {
+            matches!(record, DnsRecord::SOA { domain, .. } if is_subdomain(qname, domain) && is_subdomain(&domain.to_lowercase(), zone_cut))
+        });
+
+        let (soa, ttl) = match soa {
+            Some(soa @ DnsRecord::SOA { ttl, minimum, .. }) => (soa.clone(), (*ttl).min(*minimum)),
+            _                                               => return,
+        };
+
+        let key = match packet.header.response_code {
+            ResultCode::NXDOMAIN => EntryKey::NxDomain((qname.to_string(), qclass)),
+            ResultCode::NOERROR  => EntryKey::NoData((qname.to_string(), qtype, qclass)),
+            _                    => return,
+        };
+
+        if ttl == 0 || !self.make_room() {
+            return;
+        }
+
+        let inserted = Instant::now();
+        let entry    = NegativeEntry {
+            soa:      soa,
+            inserted: inserted,
+            ttl:      ttl,
+            expiry:   self.schedule(key.clone(), inserted, ttl),
+        };
+
+        let replaced = match key {
+            EntryKey::NxDomain(key) => self.nxdomain.insert(key, entry),
+            EntryKey::NoData(key)   => self.nodata.insert(key, entry),
+            EntryKey::Answer(_)     => None,
+        };
+
+        if let Some(replaced) = replaced {
+            self.expiry.remove(&replaced.expiry);
+        }
+    }
+
+    // Frees a slot for a new entry by dropping the entries closest to expiring, which
+    // are the expired ones first. Returns false when nothing may be cached at all.
+    fn make_room(&mut self) -> bool {
+        if self.max_entries == 0 {
+            return false;
+        }
+
+        while self.len() >= self.max_entries {
+            let Some((_, key)) = self.expiry.pop_first() else {
+                break;
+            };
+
+            self.remove(&key);
+        }
+
+        return true;
+    }
+
+    // Files a new entry in the expiry index, returning where it went
+    fn schedule(&mut self, key: EntryKey, inserted: Instant, ttl: u32) -> Expiry {
+        self.inserts += 1;
+
+        let expiry = (inserted + Duration::from_secs(ttl as u64), self.inserts);
+        self.expiry.insert(expiry, key);
+
+        return expiry;
+    }
+
+    fn remove(&mut self, key: &EntryKey) {
+        let expiry = match key {
+            EntryKey::Answer(key)   => self.entries.remove(key).map(|entry| entry.expiry),
+            EntryKey::NoData(key)   => self.nodata.remove(key).map(|entry| entry.expiry),
+            EntryKey::NxDomain(key) => self.nxdomain.remove(key).map(|entry| entry.expiry),
+        };
+
+        if let Some(expiry) = expiry {
+            self.expiry.remove(&expiry);
+        }
+    }
+
+    // Removes the negative entry once it has outlived its TTL, otherwise returns its SOA
+    // with the TTL reduced by the time spent in the cache
+    fn take_unexpired(&mut self, key: EntryKey) -> Option<DnsRecord> {
+        let entry = match key {
+            EntryKey::NoData(ref key)   => self.nodata.get(key)?,
+            EntryKey::NxDomain(ref key) => self.nxdomain.get(key)?,
+            EntryKey::Answer(_)         => return None,
+        };
+
+        let elapsed = entry.inserted.elapsed().as_secs();
+        if elapsed >= entry.ttl as u64 {
+            self.remove(&key);
+            return None;
+        }
+
+        let mut soa = entry.soa.clone();
+        soa.set_ttl(entry.ttl - elapsed as u32);
+
+        return Some(soa);
+    }
+
+    fn len(&self) -> usize {
+        return self.entries.len() + self.nodata.len() + self.nxdomain.len();
+    }
+
+    // Walks up from qname and returns the closest enclosing zone we still have NS and
+    // glue records for, along with the addresses of its nameservers
+    pub fn find_zone_cut(&mut self, qname: &str) -> Option<(String, Vec<IpAddr>)> {
+        let mut zone = qname.to_lowercase();
+
+        loop {
+            let nameservers = self.lookup(&zone, QueryType::NS, DnsClass::IN).unwrap_or_default();
+
+            let mut addrs = Vec::new();
+            for nameserver in nameservers {
+                let DnsRecord::NS { host, .. } = nameserver else {
+                    continue;
+                };
+
+                for qtype in [QueryType::A, QueryType::AAAA] {
+                    for record in self.lookup(&host, qtype, DnsClass::IN).unwrap_or_default() {
+                        match record {
+                            DnsRecord::A { addr, .. }    => addrs.push(IpAddr::V4(addr)),
+                            DnsRecord::AAAA { addr, .. } => addrs.push(IpAddr::V6(addr)),
+                            _                            => (),
+                        }
+                    }
+                }
+            }
+
+            if !addrs.is_empty() {
+                debug!("Starting at zone cut {:?} with {} nameserver addresses", zone, addrs.len());
+                return Some((zone, addrs));
+            }
+
+            match zone.split_once('.') {
+                Some((_, parent)) => zone = parent.to_string(),
+                None              => return None,
+            }
+        }
+    }
+}
+
+// The answers that a server authoritative for `zone_cut` can vouch for: records for the
+// question and the aliases it leads to, as long as they are inside the zone
+pub fn in_bailiwick(packet: &DnsPacket, zone_cut: &str) -> Vec<DnsRecord> {
+    let Some(question) = packet.question_section.first() else {
+        return Vec::new();
+    };
+
+    let mut names = vec![question.qname.to_lowercase()];
+    let mut added = true;
+    while added {
+        added = false;
+        for record in &packet.answer_section {
+            let DnsRecord::CNAME { domain, host, .. } = record else {
+                continue;
+            };
+
+            let host = host.to_lowercase();
+            if names.contains(&domain.to_lowercase()) && !names.contains(&host) {
+                names.push(host);
+                added = true;
+            }
+        }
+    }
+
+    return packet.answer_section
+                 .iter()
+                 .filter(|record| {
+                     let owner = record.get_domain().to_lowercase();
+                     names.contains(&owner) && is_subdomain(&owner, zone_cut)
+                 })
+                 .cloned()
+                 .collect();
+}
+
+// Whether `zone` can be delegated to by a server for `zone_cut` on the way to qname: it
+// has to enclose qname and be strictly below the cut
+pub fn is_delegation(qname: &str, zone: &str, zone_cut: &str) -> bool {
+    let zone = zone.to_lowercase();
+    return is_subdomain(&qname.to_lowercase(), &zone)
+           && zone != zone_cut.to_lowercase()
+           && is_subdomain(&zone, zone_cut);
+}
+
+// Whether `name` is `zone` or below it, `name` has to be lowercase already
+pub fn is_subdomain(name: &str, zone: &str) -> bool {
+    let zone = zone.to_lowercase();
+
+    return zone.is_empty()
+           || name == zone
+           || name.ends_with(&format!(".{}", zone));
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dns_question::DnsQuestion;
+
+    use super::*;
+
+    fn a(name: &str, ttl: u32) -> DnsRecord {
+        return DnsRecord::A { domain: name.to_string(), addr: [192, 0, 2, 1].into(), class: DnsClass::IN, ttl: ttl };
+    }
+
+    fn ns(zone: &str, host: &str) -> DnsRecord {
+        return DnsRecord::NS { domain: zone.to_string(), host: host.to_string(), class: DnsClass::IN, ttl: 300 };
+    }
+
+    fn soa(zone: &str, ttl: u32, minimum: u32) -> DnsRecord {
+        return DnsRecord::SOA {
+            domain:  zone.to_string(),
+            mname:   format!("ns1.{}", zone),
+            rname:   format!("hostmaster.{}", zone),
+            serial:  1,
+            refresh: 3600,
+            retry:   600,
+            expire:  86400,
+            minimum: minimum,
+            class:   DnsClass::IN,
+            ttl:     ttl,
+        };
+    }
+
+    fn response(qname: &str, qtype: QueryType, response_code: ResultCode) -> DnsPacket {
+        let mut packet              = DnsPacket::new();
+        packet.header.response_code = response_code;
+        packet.question_section.push(DnsQuestion::new(qname.to_string(), qtype, DnsClass::IN));
+        return packet;
+    }
+
+    fn cached(cache: &mut DnsCache, qname: &str, qtype: QueryType) -> Vec<String> {
+        return cache.lookup(qname, qtype, DnsClass::IN)
+                    .unwrap_or_default()
+                    .iter()
+                    .map(|record| record.to_string())
+                    .collect();
+    }
+
+    // As if every entry had been in the cache for that much longer
+    fn age(cache: &mut DnsCache, seconds: u64) {
+        let earlier = |inserted: &mut Instant| *inserted -= Duration::from_secs(seconds);
+        cache.entries.values_mut().for_each(|entry| earlier(&mut entry.inserted));
+        cache.nodata.values_mut().for_each(|entry| earlier(&mut entry.inserted));
+        cache.nxdomain.values_mut().for_each(|entry| earlier(&mut entry.inserted));
+    }
+
+    #[test]
+    fn answers_outside_the_zone_cut_are_dropped() {
+        let mut packet = response("www.example.com", QueryType::A, ResultCode::NOERROR);
+        packet.answer_section = vec![
+            DnsRecord::CNAME { domain: "www.example.com".to_string(), host: "web.example.com".to_string(), class: DnsClass::IN, ttl: 300 },
+            a("web.example.com", 300),
+            a("unrelated.example.com", 300),
+            a("www.bank.test", 300),
+        ];
+
+        let mut cache = DnsCache::new(100);
+        cache.store_packet(&packet, "example.com");
+
+        assert_eq!(cached(&mut cache, "www.example.com", QueryType::CNAME), ["www.example.com. 300 IN CNAME web.example.com."]);
+        assert_eq!(cached(&mut cache, "web.example.com", QueryType::A), ["web.example.com. 300 IN A 192.0.2.1"]);
+        assert!(cached(&mut cache, "unrelated.example.com", QueryType::A).is_empty());
+        assert!(cached(&mut cache, "www.bank.test", QueryType::A).is_empty());
+    }
+
+    #[test]
+    fn referrals_have_to_lead_below_the_zone_cut() {
+        let mut packet = response("www.sub.example.com", QueryType::A, ResultCode::NOERROR);
+        packet.authority_section = vec![
+            ns("sub.example.com", "ns.sub.example.com"),
+            ns("sub.example.com", "ns.elsewhere.test"),
+            ns("example.com", "ns.attacker.test"),
+            ns("other.example.com", "ns.other.example.com"),
+        ];
+        packet.additional_section = vec![a("ns.sub.example.com", 300), a("ns.elsewhere.test", 300), a("ns.attacker.test", 300)];
+
+        let mut cache = DnsCache::new(100);
+        cache.store_packet(&packet, "example.com");
+
+        assert_eq!(cached(&mut cache, "sub.example.com", QueryType::NS), [
+            "sub.example.com. 300 IN NS ns.sub.example.com.",
+            "sub.example.com. 300 IN NS ns.elsewhere.test.",
+        ]);
+        assert!(cached(&mut cache, "example.com", QueryType::NS).is_empty());
+        assert!(cached(&mut cache, "other.example.com", QueryType::NS).is_empty());
+
+        // Glue only for hosts inside the cut
+        assert_eq!(cached(&mut cache, "ns.sub.example.com", QueryType::A).len(), 1);
+        assert!(cached(&mut cache, "ns.elsewhere.test", QueryType::A).is_empty());
+        assert!(cached(&mut cache, "ns.attacker.test", QueryType::A).is_empty());
+    }
+
+    #[test]
+    fn full_cache_drops_what_expires_first() {
+        let mut cache = DnsCache::new(3);
+        cache.store(&[a("x.test", 300), a("y.test", 100), a("z.test", 200)]);
+        cache.store(&[a("w.test", 400)]);
+
+        assert!(cached(&mut cache, "y.test", QueryType::A).is_empty());
+        assert_eq!(cache.len(), 3);
+
+        // Replacing an entry moves it in the index rather than adding to it
+        cache.store(&[a("x.test", 50)]);
+        assert_eq!(cache.expiry.len(), 3);
+
+        cache.store(&[a("v.test", 300)]);
+        assert!(cached(&mut cache, "x.test", QueryType::A).is_empty());
+        assert_eq!(cached(&mut cache, "z.test", QueryType::A).len(), 1);
+        assert_eq!(cache.expiry.len(), 3);
+
+        // Nothing is kept without room for it
+        let mut cache = DnsCache::new(0);
+        cache.store(&[a("x.test", 300)]);
+        assert!(cached(&mut cache, "x.test", QueryType::A).is_empty());
+    }
+
+    fn negative(cache: &mut DnsCache, qname: &str, qtype: QueryType) -> Option<(u16, String)> {
+        return cache.lookup_negative(qname, qtype, DnsClass::IN)
+                    .map(|(response_code, soa)| (response_code.to_num(), soa.to_string()));
+    }
+
+    #[test]
+    fn negative_ttl_is_the_lesser_of_soa_ttl_and_minimum() {
+        let mut cache = DnsCache::new(100);
+
+        let mut nxdomain = response("gone.example.com", QueryType::A, ResultCode::NXDOMAIN);
+        nxdomain.authority_section.push(soa("example.com", 300, 60));
+        cache.store_packet(&nxdomain, "example.com");
+
+        let mut nodata = response("www.example.com", QueryType::AAAA, ResultCode::NOERROR);
+        nodata.authority_section.push(soa("example.com", 30, 60));
+        cache.store_packet(&nodata, "example.com");
+
+        // NXDOMAIN holds for every type, NODATA only for the one asked for
+        assert_eq!(negative(&mut cache, "gone.example.com", QueryType::MX),
+                   Some((3, "example.com. 60 IN SOA ns1.example.com. hostmaster.example.com. 1 3600 600 86400 60".to_string())));
+        assert_eq!(negative(&mut cache, "www.example.com", QueryType::AAAA),
+                   Some((0, "example.com. 30 IN SOA ns1.example.com. hostmaster.example.com. 1 3600 600 86400 60".to_string())));
+        assert_eq!(negative(&mut cache, "www.example.com", QueryType::A), None);
+
+        // Nothing to cache without an SOA, or with a TTL of 0
+        let mut cache = DnsCache::new(100);
+        cache.store_packet(&response("gone.example.com", QueryType::A, ResultCode::NXDOMAIN), "example.com");
+
+        let mut uncacheable = response("www.example.com", QueryType::AAAA, ResultCode::NOERROR);
+        uncacheable.authority_section.push(soa("example.com", 300, 0));
+        cache.store_packet(&uncacheable, "example.com");
+
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn negative_soa_has_to_be_inside_the_zone_cut() {
+        let mut cache = DnsCache::new(100);
+
+        // Above the cut, and for a zone that doesn't enclose the name
+        for zone in ["com", "example.org"] {
+            let mut packet = response("gone.example.com", QueryType::A, ResultCode::NXDOMAIN);
+            packet.authority_section.push(soa(zone, 300, 60));
+            cache.store_packet(&packet, "example.com");
+        }
+
+        assert_eq!(negative(&mut cache, "gone.example.com", QueryType::A), None);
+
+        // A zone below the cut can prove it
+        let mut packet = response("gone.sub.example.com", QueryType::A, ResultCode::NXDOMAIN);
+        packet.authority_section.push(soa("sub.example.com", 300, 60));
+        cache.store_packet(&packet, "example.com");
+
+        assert_eq!(negative(&mut cache, "gone.sub.example.com", QueryType::A).map(|(response_code, _)| response_code), Some(3));
+    }
+
+    #[test]
+    fn negative_entries_expire() {
+        let mut cache = DnsCache::new(100);
+
+        let mut packet = response("gone.example.com", QueryType::A, ResultCode::NXDOMAIN);
+        packet.authority_section.push(soa("example.com", 300, 60));
+        cache.store_packet(&packet, "example.com");
+
+        age(&mut cache, 45);
+        assert_eq!(negative(&mut cache, "gone.example.com", QueryType::A),
+                   Some((3, "example.com. 15 IN SOA ns1.example.com. hostmaster.example.com. 1 3600 600 86400 60".to_string())));
+
+        age(&mut cache, 15);
+        assert_eq!(negative(&mut cache, "gone.example.com", QueryType::A), None);
+        assert_eq!(cache.len(), 0);
+        assert!(cache.expiry.is_empty());
+    }
+}
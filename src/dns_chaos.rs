@@ -0,0 +1,49 @@
+use std::fs;
+
+use crate::dns_class::DnsClass;
+use crate::dns_packet::DnsPacket;
+use crate::dns_query_type::QueryType;
+use crate::dns_question::DnsQuestion;
+use crate::dns_record::DnsRecord;
+use crate::dns_result_code::ResultCode;
+
+// Answers the CHAOS class names servers use to identify themselves, like
+// `dig CH TXT version.bind`. Anything else in CH is refused.
+pub fn resolve(question: &DnsQuestion) -> DnsPacket {
+    let mut result                     = DnsPacket::new();
+    result.header.authoritative_answer = true;
+
+    let text = match question.qname.to_lowercase().as_str() {
+        "version.bind" | "version.server" => {
+            format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))
+        },
+        "hostname.bind" | "id.server" => hostname(),
+        _ => {
+            result.header.response_code = ResultCode::REFUSED;
+            return result;
+        }
+    };
+
+    // Other types get an empty NOERROR answer, the name does exist
+    if matches!(question.qtype, QueryType::TXT | QueryType::UNKNOWN(255)) {
+        result.answer_section.push(DnsRecord::TXT {
+            domain: question.qname.clone(),
+            data:   vec![text.into_bytes()],
+            class:  DnsClass::CH,
+            ttl:    0,
+        });
+    }
+
+    return result;
+}
+
+fn hostname() -> String {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname")
+                      .or_else(|_| fs::read_to_string("/etc/hostname"))
+                      .unwrap_or_default();
+
+    match hostname.trim() {
+        ""       => "localhost".to_string(),
+        hostname => hostname.to_string(),
+    }
+}
@@ -0,0 +1,45 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DnsClass {
+    UNKNOWN(u16),
+    IN,
+    CH,
+    HS,
+    NONE,
+    ANY,
+}
+
+impl DnsClass {
+    pub fn to_num(self) -> u16 {
+        match self {
+            Self::UNKNOWN(x) => x,
+            Self::IN         => 1,
+            Self::CH         => 3,
+            Self::HS         => 4,
+            Self::NONE       => 254,
+            Self::ANY        => 255,
+        }
+    }
+
+    pub fn from_num(num: u16) -> Self {
+        match num {
+            1   => Self::IN,
+            3   => Self::CH,
+            4   => Self::HS,
+            254 => Self::NONE,
+            255 => Self::ANY,
+            _   => Self::UNKNOWN(num),
+        }
+    }
+}
+
+// Mnemonic of the class, or CLASS12345 for the ones we don't know (RFC 3597 5)
+impl fmt::Display for DnsClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::UNKNOWN(x) => write!(f, "CLASS{}", x),
+            _                => write!(f, "{:?}", self),
+        }
+    }
+}
@@ -0,0 +1,24 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DnsError {
+    EndOfBuffer,
+    LabelTooLong,
+    PointerLoop,
+    BadRdLength,
+    BufferFull,
+}
+
+impl fmt::Display for DnsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::EndOfBuffer  => write!(f, "read past the end of the buffer"),
+            Self::LabelTooLong => write!(f, "label or name exceeds the maximum length"),
+            Self::PointerLoop  => write!(f, "compression pointer does not point to an earlier name"),
+            Self::BadRdLength  => write!(f, "RDLENGTH does not match the record data"),
+            Self::BufferFull   => write!(f, "message does not fit the size limit"),
+        }
+    }
+}
+
+impl std::error::Error for DnsError {}
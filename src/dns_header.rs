@@ -1,7 +1,8 @@
+use crate::dns_error::DnsError;
 use crate::packet_buffer::PacketBuffer;
 use crate::dns_result_code::ResultCode;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DnsHeader {
     pub packet_identifier:    u16,
 
@@ -44,14 +45,14 @@ impl DnsHeader {
         }
     }
 
-    pub fn read(&mut self, buffer: &mut PacketBuffer) {
-        self.packet_identifier      = buffer.read_u16();
+    pub fn read(&mut self, buffer: &mut PacketBuffer) -> Result<(), DnsError> {
+        self.packet_identifier      = buffer.read_u16()?;
 
-        let flag                    = buffer.read_u16();
+        let flag                    = buffer.read_u16()?;
         let left                    = (flag >> 8) as u8;
         let right                   = (flag & 0xFF) as u8;
         self.query_response         = (left & (1 << 7)) > 0;
-        self.operation_code         = (left >> 1) & 0x0F;
+        self.operation_code         = (left >> 3) & 0x0F;
         self.authoritative_answer   = (left & (1 << 2)) > 0;
         self.truncated_message      = (left & (1 << 1)) > 0;
         self.recursion_desired      = (left & (1 << 0)) > 0;
@@ -60,32 +61,37 @@ impl DnsHeader {
         self.reserved               = (right & (1 << 6)) > 0;
         self.authed_data            = (right & (1 << 5)) > 0;
         self.checking_disabled      = (right & (1 << 4)) > 0;
-        self.response_code          = ResultCode::from_num(right & 0x0F);
+        self.response_code          = ResultCode::from_num((right & 0x0F) as u16);
 
-        self.question_count         = buffer.read_u16();
-        self.answer_count           = buffer.read_u16();
-        self.authority_count        = buffer.read_u16();
-        self.additional_count       = buffer.read_u16();
+        self.question_count         = buffer.read_u16()?;
+        self.answer_count           = buffer.read_u16()?;
+        self.authority_count        = buffer.read_u16()?;
+        self.additional_count       = buffer.read_u16()?;
+
+        return Ok(());
     }
 
-    pub fn write(&self, buffer: &mut PacketBuffer) {
-        buffer.write_u16(self.packet_identifier);
+    pub fn write(&self, buffer: &mut PacketBuffer) -> Result<(), DnsError> {
+        buffer.write_u16(self.packet_identifier)?;
 
         buffer.write_u8((self.recursion_desired as u8)
                             | ((self.truncated_message as u8) << 1)
                             | ((self.authoritative_answer as u8) << 2)
                             | (self.operation_code << 3)
-                            | ((self.query_response as u8) << 7));
+                            | ((self.query_response as u8) << 7))?;
 
-        buffer.write_u8((self.response_code as u8)
+        // Only the lower 4 bits fit here, an OPT record carries the rest
+        buffer.write_u8(((self.response_code.to_num() as u8) & 0x0F)
                             | ((self.checking_disabled as u8) << 4)
                             | ((self.authed_data as u8) << 5)
                             | ((self.reserved as u8) << 6)
-                            | ((self.recursion_available as u8) << 7));
+                            | ((self.recursion_available as u8) << 7))?;
+
+        buffer.write_u16(self.question_count)?;
+        buffer.write_u16(self.answer_count)?;
+        buffer.write_u16(self.authority_count)?;
+        buffer.write_u16(self.additional_count)?;
 
-        buffer.write_u16(self.question_count);
-        buffer.write_u16(self.answer_count);
-        buffer.write_u16(self.authority_count);
-        buffer.write_u16(self.additional_count);
+        return Ok(());
     }
 }
\ No newline at end of file
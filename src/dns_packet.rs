@@ -1,9 +1,15 @@
+use std::net::IpAddr;
+
+use crate::dns_class::DnsClass;
+use crate::dns_error::DnsError;
 use crate::dns_header::DnsHeader;
 use crate::dns_query_type::QueryType;
 use crate::dns_question::DnsQuestion;
 use crate::dns_record::DnsRecord;
-use crate::packet_buffer::PacketBuffer;
+use crate::dns_result_code::ResultCode;
+use crate::packet_buffer::{PacketBuffer, EDNS_PACKET_SIZE, UDP_PACKET_SIZE};
 
+#[derive(Clone)]
 pub struct DnsPacket
 {
     pub header:             DnsHeader,
@@ -24,55 +30,254 @@ impl DnsPacket {
         }
     }
 
-    pub fn get_packet_from_buffer(buffer: &mut PacketBuffer) -> Self {
+    pub fn get_packet_from_buffer(buffer: &mut PacketBuffer) -> Result<Self, DnsError> {
         let mut result = Self::new();
-        result.header.read(buffer);
+        result.header.read(buffer)?;
 
         for _ in 0..result.header.question_count {
-            let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0));
-            question.read(buffer);
+            let mut question = DnsQuestion::new("".to_string(), QueryType::UNKNOWN(0), DnsClass::IN);
+            question.read(buffer)?;
             result.question_section.push(question);
         }
 
         for _ in 0..result.header.answer_count {
-            let answer = DnsRecord::read(buffer);
+            let answer = DnsRecord::read(buffer)?;
             result.answer_section.push(answer);
         }
 
         for _ in 0..result.header.authority_count {
-            let authority = DnsRecord::read(buffer);
+            let authority = DnsRecord::read(buffer)?;
             result.authority_section.push(authority);
         }
 
         for _ in 0..result.header.additional_count {
-            let additional = DnsRecord::read(buffer);
+            let additional = DnsRecord::read(buffer)?;
             result.additional_section.push(additional);
         }
 
-        return result;
+        if let Some(DnsRecord::OPT { extended_rcode, .. }) = result.get_opt() {
+            let response_code           = ((*extended_rcode as u16) << 4) | result.header.response_code.to_num();
+            result.header.response_code = ResultCode::from_num(response_code);
+        }
+
+        return Ok(result);
     }
 
-    pub fn write_packet_to_buffer(&mut self, buffer: &mut PacketBuffer) {
+    pub fn write_packet_to_buffer(&mut self, buffer: &mut PacketBuffer) -> Result<(), DnsError> {
         self.header.question_count   = self.question_section.len() as u16;
         self.header.answer_count     = self.answer_section.len() as u16;
         self.header.authority_count  = self.authority_section.len() as u16;
         self.header.additional_count = self.additional_section.len() as u16;
-        self.header.write(buffer);
+        self.header.write(buffer)?;
+
+        // The header only holds the lower 4 bits of the response code
+        for additional in &mut self.additional_section {
+            if let DnsRecord::OPT { ref mut extended_rcode, .. } = additional {
+                *extended_rcode = (self.header.response_code.to_num() >> 4) as u8;
+            }
+        }
 
         for question in &self.question_section {
-            question.write(buffer);
+            question.write(buffer)?;
         }
 
         for answer in &self.answer_section {
-            answer.write(buffer);
+            answer.write(buffer)?;
         }
 
         for authority in &self.authority_section {
-            authority.write(buffer);
+            authority.write(buffer)?;
         }
 
         for additional in &self.additional_section {
-            additional.write(buffer);
+            additional.write(buffer)?;
+        }
+
+        return Ok(());
+    }
+
+    pub fn get_opt(&self) -> Option<&DnsRecord> {
+        return self.additional_section
+                   .iter()
+                   .find(|record| matches!(record, DnsRecord::OPT { .. }));
+    }
+
+    // Largest UDP response the sender of this packet can receive, at least 512 bytes
+    // and never more than we are willing to send
+    pub fn get_udp_payload_size(&self) -> usize {
+        match self.get_opt() {
+            Some(DnsRecord::OPT { payload_size, .. }) => {
+                (*payload_size as usize).clamp(UDP_PACKET_SIZE, EDNS_PACKET_SIZE)
+            },
+            _ => UDP_PACKET_SIZE,
+        }
+    }
+
+    // Addresses from the additional section for the nameservers a referral points at
+    pub fn get_glue(&self) -> Vec<IpAddr> {
+        let mut glue = Vec::new();
+
+        for record in &self.authority_section {
+            let DnsRecord::NS { host, .. } = record else {
+                continue;
+            };
+
+            for additional in &self.additional_section {
+                match additional {
+                    DnsRecord::A { domain, addr, .. } if domain.eq_ignore_ascii_case(host) => {
+                        glue.push(IpAddr::V4(*addr));
+                    },
+                    DnsRecord::AAAA { domain, addr, .. } if domain.eq_ignore_ascii_case(host) => {
+                        glue.push(IpAddr::V6(*addr));
+                    },
+                    _ => (),
+                }
+            }
+        }
+
+        return glue;
+    }
+
+    // Whether this packet is the reply to `request`: same ID, same question. A FORMERR
+    // may leave the question out, since the server couldn't make sense of it.
+    pub fn is_response_to(&self, request: &DnsPacket) -> bool {
+        if !self.header.query_response || self.header.packet_identifier != request.header.packet_identifier {
+            return false;
+        }
+
+        if self.question_section.is_empty() && matches!(self.header.response_code, ResultCode::FORMERR) {
+            return true;
+        }
+
+        return self.question_section.len() == request.question_section.len()
+               && self.question_section.iter().zip(&request.question_section).all(|(answer, question)| {
+                   answer.qname.eq_ignore_ascii_case(&question.qname)
+                   && answer.qtype == question.qtype
+                   && answer.qclass == question.qclass
+               });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read(data: &[u8]) -> Result<DnsPacket, DnsError> {
+        return DnsPacket::get_packet_from_buffer(&mut PacketBuffer::from_bytes(data));
+    }
+
+    fn response() -> Vec<u8> {
+        let mut packet = DnsPacket::new();
+        packet.question_section.push(DnsQuestion::new("example.com".to_string(), QueryType::A, DnsClass::IN));
+        packet.answer_section.push(DnsRecord::A {
+            domain: "example.com".to_string(),
+            addr:   [192, 0, 2, 1].into(),
+            class:  DnsClass::IN,
+            ttl:    300,
+        });
+
+        let mut buffer = PacketBuffer::with_limit(UDP_PACKET_SIZE);
+        packet.write_packet_to_buffer(&mut buffer).unwrap();
+        return buffer.get_data().to_vec();
+    }
+
+    #[test]
+    fn truncated_packets_are_refused() {
+        let data = response();
+        assert!(read(&data).is_ok());
+
+        // Anywhere in the header, the question or the record
+        for len in 0..data.len() {
+            assert_eq!(read(&data[..len]).err(), Some(DnsError::EndOfBuffer), "cut at {}", len);
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rdlength_has_to_match_the_data() {
+        let mut data = response();
+        let rdlength = data.len() - 6;
+        data[rdlength + 1] = 5;
+        data.push(0);
+        assert_eq!(read(&data).err(), Some(DnsError::BadRdLength));
+    }
+
+    fn occurrences(data: &[u8], needle: &[u8]) -> usize {
+        return data.windows(needle.len()).filter(|window| *window == needle).count();
+    }
+
+    #[test]
+    fn repeated_names_are_compressed() {
+        let mut packet = DnsPacket::new();
+        packet.question_section.push(DnsQuestion::new("example.com".to_string(), QueryType::MX, DnsClass::IN));
+        packet.answer_section.push(DnsRecord::MX {
+            domain:   "example.com".to_string(),
+            priority: 10,
+            host:     "mail.example.com".to_string(),
+            class:    DnsClass::IN,
+            ttl:      300,
+        });
+        packet.authority_section.push(DnsRecord::NS {
+            domain: "example.com".to_string(),
+            host:   "ns1.example.com".to_string(),
+            class:  DnsClass::IN,
+            ttl:    300,
+        });
+        packet.authority_section.push(DnsRecord::SOA {
+            domain:  "example.com".to_string(),
+            mname:   "ns1.example.com".to_string(),
+            rname:   "hostmaster.example.com".to_string(),
+            serial:  1,
+            refresh: 3600,
+            retry:   600,
+            expire:  86400,
+            minimum: 300,
+            class:   DnsClass::IN,
+            ttl:     300,
+        });
+
+        let mut buffer = PacketBuffer::with_limit(UDP_PACKET_SIZE);
+        packet.write_packet_to_buffer(&mut buffer).unwrap();
+        let data = buffer.get_data();
+
+        // Written out once in the question, pointed at everywhere else
+        assert_eq!(occurrences(data, b"\x07example\x03com\x00"), 1);
+        assert_eq!(occurrences(data, &[0xC0, 0x0C]), 6);
+
+        // The SOA MNAME points at the NS host
+        assert_eq!(occurrences(data, b"\x03ns1"), 1);
+
+        let parsed  = read(data).unwrap();
+        let records = |packet: &DnsPacket| -> Vec<String> {
+            return packet.answer_section.iter()
+                         .chain(&packet.authority_section)
+                         .map(|record| record.to_string())
+                         .collect();
+        };
+        assert_eq!(parsed.question_section[0].qname, "example.com");
+        assert_eq!(records(&parsed), records(&packet));
+    }
+
+    #[test]
+    fn srv_targets_are_not_compressed() {
+        let mut packet = DnsPacket::new();
+        packet.question_section.push(DnsQuestion::new("_sip._udp.example.com".to_string(), QueryType::SRV, DnsClass::IN));
+        packet.answer_section.push(DnsRecord::SRV {
+            domain:   "_sip._udp.example.com".to_string(),
+            priority: 10,
+            weight:   5,
+            port:     5060,
+            host:     "sip.example.com".to_string(),
+            class:    DnsClass::IN,
+            ttl:      300,
+        });
+
+        let mut buffer = PacketBuffer::with_limit(UDP_PACKET_SIZE);
+        packet.write_packet_to_buffer(&mut buffer).unwrap();
+        let data = buffer.get_data();
+
+        assert!(data.ends_with(b"\x03sip\x07example\x03com\x00"));
+
+        let parsed = read(data).unwrap();
+        assert_eq!(parsed.answer_section[0].to_string(), packet.answer_section[0].to_string());
+    }
+}
@@ -1,22 +1,34 @@
-#[derive(Clone, Copy, Debug)]
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum QueryType {
     UNKNOWN(u16),
     A,
     NS,
     CNAME,
+    SOA,
+    PTR,
     MX,
+    TXT,
     AAAA,
+    SRV,
+    OPT,
 }
 
 impl QueryType {
-    pub fn to_num(&self) -> u16 {
-        match *self {
+    pub fn to_num(self) -> u16 {
+        match self {
             Self::UNKNOWN(x) => x,
             Self::A          => 1,
             Self::NS         => 2,
             Self::CNAME      => 5,
+            Self::SOA        => 6,
+            Self::PTR        => 12,
             Self::MX         => 15,
+            Self::TXT        => 16,
             Self::AAAA       => 28,
+            Self::SRV        => 33,
+            Self::OPT        => 41,
         }
     }
 
@@ -25,9 +37,29 @@ impl QueryType {
             1  => Self::A,
             2  => Self::NS,
             5  => Self::CNAME,
+            6  => Self::SOA,
+            12 => Self::PTR,
             15 => Self::MX,
+            16 => Self::TXT,
             28 => Self::AAAA,
+            33 => Self::SRV,
+            41 => Self::OPT,
             _  => Self::UNKNOWN(num),
         }
     }
-}
\ No newline at end of file
+
+    // Types defined by DNSSEC: DS, RRSIG, NSEC, DNSKEY, NSEC3 and NSEC3PARAM
+    pub fn is_dnssec(self) -> bool {
+        return matches!(self.to_num(), 43 | 46 | 47 | 48 | 50 | 51);
+    }
+}
+
+// Mnemonic of the type, or TYPE12345 for the ones we don't know (RFC 3597 5)
+impl fmt::Display for QueryType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::UNKNOWN(x) => write!(f, "TYPE{}", x),
+            _                => write!(f, "{:?}", self),
+        }
+    }
+}
@@ -1,29 +1,44 @@
+use crate::dns_class::DnsClass;
+use crate::dns_error::DnsError;
 use crate::packet_buffer::PacketBuffer;
 use crate::dns_query_type::QueryType;
+use std::fmt;
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct DnsQuestion {
-    pub qname: String,
-    pub qtype: QueryType,
+    pub qname:  String,
+    pub qtype:  QueryType,
+    pub qclass: DnsClass,
 }
 
 impl DnsQuestion {
-    pub fn new(qname: String, qtype: QueryType) -> Self {
+    pub fn new(qname: String, qtype: QueryType, qclass: DnsClass) -> Self {
         Self {
-            qname: qname,
-            qtype: qtype,
+            qname:  qname,
+            qtype:  qtype,
+            qclass: qclass,
         }
     }
 
-    pub fn read(&mut self, buffer: &mut PacketBuffer) {
-        self.qname = buffer.get_qname();
-        self.qtype = QueryType::from_num(buffer.read_u16());
-        let _      = buffer.read_u16(); // Class is always 1, discard this data
+    pub fn read(&mut self, buffer: &mut PacketBuffer) -> Result<(), DnsError> {
+        self.qname  = buffer.get_qname()?;
+        self.qtype  = QueryType::from_num(buffer.read_u16()?);
+        self.qclass = DnsClass::from_num(buffer.read_u16()?);
+
+        return Ok(());
+    }
+
+    pub fn write(&self, buffer: &mut PacketBuffer) -> Result<(), DnsError> {
+        buffer.write_qname(&self.qname)?;
+        buffer.write_u16(self.qtype.to_num())?;
+        buffer.write_u16(self.qclass.to_num())?;
+
+        return Ok(());
     }
+}
 
-    pub fn write(&self, buffer: &mut PacketBuffer) {
-        buffer.write_qname(&self.qname);
-        buffer.write_u16(self.qtype.to_num());
-        buffer.write_u16(1);
+impl fmt::Display for DnsQuestion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}. {} {}", self.qname, self.qclass, self.qtype)
     }
-}
\ No newline at end of file
+}
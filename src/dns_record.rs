@@ -1,210 +1,673 @@
+use crate::dns_class::DnsClass;
+use crate::dns_error::DnsError;
 use crate::packet_buffer::PacketBuffer;
 use crate::dns_query_type::QueryType;
+use crate::edns_option::EdnsOption;
+use std::fmt;
 use std::net::{Ipv4Addr, Ipv6Addr};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum DnsRecord {
+    // Any type we don't have a variant for, with its RDATA kept as is (RFC 3597)
     UNKNOWN {
         domain: String,
         qtype:  u16,
+        data:   Vec<u8>,
+        class:  DnsClass,
         ttl:    u32,
-        len:    u16,
     },
     A {
         domain: String,
         addr:   Ipv4Addr,
+        class:  DnsClass,
         ttl:    u32,
     }, // 1
     NS {
         domain: String,
         host:   String,
+        class:  DnsClass,
         ttl:    u32,
     }, // 2
     CNAME {
         domain: String,
         host:   String,
+        class:  DnsClass,
         ttl:    u32,
     }, // 5
+    SOA {
+        domain:  String,
+        mname:   String,
+        rname:   String,
+        serial:  u32,
+        refresh: u32,
+        retry:   u32,
+        expire:  u32,
+        minimum: u32,
+        class:   DnsClass,
+        ttl:     u32,
+    }, // 6
+    PTR {
+        domain: String,
+        host:   String,
+        class:  DnsClass,
+        ttl:    u32,
+    }, // 12
     MX {
         domain:   String,
         priority: u16,
         host:     String,
+        class:    DnsClass,
         ttl:      u32,
     }, // 15
+    TXT {
+        domain: String,
+        data:   Vec<Vec<u8>>, // Each character-string, at most 255 bytes
+        class:  DnsClass,
+        ttl:    u32,
+    }, // 16
     AAAA {
         domain: String,
         addr:   Ipv6Addr,
+        class:  DnsClass,
         ttl:    u32,
-    } // 28
+    }, // 28
+    SRV {
+        domain:   String,
+        priority: u16,
+        weight:   u16,
+        port:     u16,
+        host:     String,
+        class:    DnsClass,
+        ttl:      u32,
+    }, // 33
+    // Pseudo-record that repurposes CLASS as the UDP payload size and TTL as
+    // extended RCODE, version and flags (RFC 6891 6.1.3)
+    OPT {
+        payload_size:   u16,
+        extended_rcode: u8,
+        version:        u8,
+        dnssec_ok:      bool,
+        options:        Vec<EdnsOption>,
+    } // 41
 }
 
 impl DnsRecord {
-    pub fn read(buffer: &mut PacketBuffer) -> Self {
-        let domain = buffer.get_qname();
-        let qtype  = buffer.read_u16();
-        let _      = buffer.read_u16(); // Class is always 1, discard this data
-        let ttl    = buffer.read_u32();
-        let len    = buffer.read_u16();
-
-        match qtype {
-            1 => Self::A {
+    pub fn read(buffer: &mut PacketBuffer) -> Result<Self, DnsError> {
+        let domain = buffer.get_qname()?;
+        let qtype  = buffer.read_u16()?;
+        let class  = buffer.read_u16()?; // Payload size rather than a class for OPT
+        let ttl    = buffer.read_u32()?;
+        let len    = buffer.read_u16()?;
+
+        let data_start = buffer.get_pos();
+        let qclass     = DnsClass::from_num(class);
+
+        // UPDATE names whole RRsets with classes ANY and NONE and no RDATA (RFC 2136 2.4)
+        if len == 0 && qtype != 41 && matches!(qclass, DnsClass::ANY | DnsClass::NONE) {
+            return Ok(Self::UNKNOWN {
                 domain: domain,
-                addr: Ipv4Addr::new(
-                    buffer.read(),
-                    buffer.read(),
-                    buffer.read(),
-                    buffer.read(),
-                ),
+                qtype: qtype,
+                data: Vec::new(),
+                class: qclass,
                 ttl: ttl
+            });
+        }
+        let record = match qtype {
+            1 => {
+                if len != 4 {
+                    return Err(DnsError::BadRdLength);
+                }
+
+                Self::A {
+                    domain: domain,
+                    addr: Ipv4Addr::new(
+                        buffer.read()?,
+                        buffer.read()?,
+                        buffer.read()?,
+                        buffer.read()?,
+                    ),
+                    class: qclass,
+                    ttl: ttl
+                }
             },
             2 => {
-                let host = buffer.get_qname();
+                let host = buffer.get_qname()?;
                 Self::NS {
                     domain: domain,
                     host: host,
+                    class: qclass,
                     ttl: ttl
                 }
             },
             5 => {
-                let host = buffer.get_qname();
+                let host = buffer.get_qname()?;
                 Self::CNAME {
                     domain: domain,
                     host: host,
+                    class: qclass,
+                    ttl: ttl
+                }
+            },
+            6 => {
+                let mname   = buffer.get_qname()?;
+                let rname   = buffer.get_qname()?;
+                let serial  = buffer.read_u32()?;
+                let refresh = buffer.read_u32()?;
+                let retry   = buffer.read_u32()?;
+                let expire  = buffer.read_u32()?;
+                let minimum = buffer.read_u32()?;
+                Self::SOA {
+                    domain: domain,
+                    mname: mname,
+                    rname: rname,
+                    serial: serial,
+                    refresh: refresh,
+                    retry: retry,
+                    expire: expire,
+                    minimum: minimum,
+                    class: qclass,
+                    ttl: ttl
+                }
+            },
+            12 => {
+                let host = buffer.get_qname()?;
+                Self::PTR {
+                    domain: domain,
+                    host: host,
+                    class: qclass,
                     ttl: ttl
                 }
             },
             15 => {
-                let priority = buffer.read_u16();
-                let host = buffer.get_qname();
+                let priority = buffer.read_u16()?;
+                let host = buffer.get_qname()?;
                 Self::MX {
                     domain: domain,
                     priority: priority,
                     host: host,
+                    class: qclass,
+                    ttl: ttl
+                }
+            },
+            16 => {
+                let mut data = Vec::new();
+                while buffer.get_pos() < data_start + len as usize {
+                    let string_len = buffer.read()?;
+                    data.push(buffer.read_bytes(string_len as usize)?);
+                }
+
+                Self::TXT {
+                    domain: domain,
+                    data: data,
+                    class: qclass,
                     ttl: ttl
                 }
             },
             28 => {
+                if len != 16 {
+                    return Err(DnsError::BadRdLength);
+                }
+
                 Self::AAAA {
                     domain: domain,
                     addr: Ipv6Addr::new(
-                        buffer.read_u16(),
-                        buffer.read_u16(),
-                        buffer.read_u16(),
-                        buffer.read_u16(),
-                        buffer.read_u16(),
-                        buffer.read_u16(),
-                        buffer.read_u16(),
-                        buffer.read_u16(),
+                        buffer.read_u16()?,
+                        buffer.read_u16()?,
+                        buffer.read_u16()?,
+                        buffer.read_u16()?,
+                        buffer.read_u16()?,
+                        buffer.read_u16()?,
+                        buffer.read_u16()?,
+                        buffer.read_u16()?,
                     ),
+                    class: qclass,
                     ttl: ttl
                 }
             },
-            _ => {
-                buffer.step_pos(len as usize);
+            33 => {
+                let priority = buffer.read_u16()?;
+                let weight = buffer.read_u16()?;
+                let port = buffer.read_u16()?;
+                let host = buffer.get_qname()?;
+                Self::SRV {
+                    domain: domain,
+                    priority: priority,
+                    weight: weight,
+                    port: port,
+                    host: host,
+                    class: qclass,
+                    ttl: ttl
+                }
+            },
+            41 => {
+                let mut options = Vec::new();
+                while buffer.get_pos() < data_start + len as usize {
+                    options.push(EdnsOption::read(buffer)?);
+                }
 
+                Self::OPT {
+                    payload_size: class,
+                    extended_rcode: (ttl >> 24) as u8,
+                    version: ((ttl >> 16) & 0xFF) as u8,
+                    dnssec_ok: (ttl & 0x8000) > 0,
+                    options: options
+                }
+            },
+            _ => {
+                let data = buffer.read_bytes(len as usize)?;
                 Self::UNKNOWN {
                     domain: domain,
                     qtype: qtype,
-                    ttl: ttl,
-                    len: len
+                    data: data,
+                    class: qclass,
+                    ttl: ttl
                 }
             }
+        };
+
+        // The record data has to consume exactly RDLENGTH bytes
+        if buffer.get_pos() != data_start + len as usize {
+            return Err(DnsError::BadRdLength);
         }
+
+        return Ok(record);
     }
 
-    pub fn write(&self, buffer: &mut PacketBuffer) {
+    pub fn write(&self, buffer: &mut PacketBuffer) -> Result<(), DnsError> {
         match *self {
             DnsRecord::A {
                 ref domain,
                 ref addr,
+                class,
                 ttl
             } => {
-                buffer.write_qname(domain);
-                buffer.write_u16(QueryType::A.to_num());
-                buffer.write_u16(1);
-                buffer.write_u32(ttl);
-                buffer.write_u16(4);
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::A.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(4)?;
 
                 let octets = addr.octets();
-                buffer.write_u8(octets[0]);
-                buffer.write_u8(octets[1]);
-                buffer.write_u8(octets[2]);
-                buffer.write_u8(octets[3]);
+                buffer.write_u8(octets[0])?;
+                buffer.write_u8(octets[1])?;
+                buffer.write_u8(octets[2])?;
+                buffer.write_u8(octets[3])?;
             },
             DnsRecord::NS {
                 ref domain,
                 ref host,
+                class,
                 ttl
             } => {
-                buffer.write_qname(domain);
-                buffer.write_u16(QueryType::NS.to_num());
-                buffer.write_u16(1);
-                buffer.write_u32(ttl);
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::NS.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
 
                 // We don't know ahead of time the number of bytes needed, since we might
                 // end up using jumps to compress the size. We'll solve this by writing
                 // a zero size and then going back to fill in the size needed.
                 let pos = buffer.get_pos();
-                buffer.write_u16(0);
-                buffer.write_qname(host);
+                buffer.write_u16(0)?;
+                buffer.write_qname(host)?;
 
                 let size = buffer.get_pos() - (pos + 2);
-                buffer.set_u16(pos, size as u16);
+                buffer.set_u16(pos, size as u16)?;
             },
             DnsRecord::CNAME {
                 ref domain,
                 ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::CNAME.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.get_pos();
+                buffer.write_u16(0)?;
+                buffer.write_qname(host)?;
+
+                let size = buffer.get_pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::SOA {
+                ref domain,
+                ref mname,
+                ref rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SOA.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.get_pos();
+                buffer.write_u16(0)?;
+                buffer.write_qname(mname)?;
+                buffer.write_qname(rname)?;
+                buffer.write_u32(serial)?;
+                buffer.write_u32(refresh)?;
+                buffer.write_u32(retry)?;
+                buffer.write_u32(expire)?;
+                buffer.write_u32(minimum)?;
+
+                let size = buffer.get_pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::PTR {
+                ref domain,
+                ref host,
+                class,
                 ttl,
             } => {
-                buffer.write_qname(domain);
-                buffer.write_u16(QueryType::CNAME.to_num());
-                buffer.write_u16(1);
-                buffer.write_u32(ttl);
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::PTR.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
 
                 let pos = buffer.get_pos();
-                buffer.write_u16(0);
-                buffer.write_qname(host);
+                buffer.write_u16(0)?;
+                buffer.write_qname(host)?;
 
                 let size = buffer.get_pos() - (pos + 2);
-                buffer.set_u16(pos, size as u16);
+                buffer.set_u16(pos, size as u16)?;
             },
             DnsRecord::MX {
                 ref domain,
                 priority,
                 ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::MX.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.get_pos();
+                buffer.write_u16(0)?;
+                buffer.write_u16(priority)?;
+                buffer.write_qname(host)?;
+
+                let size = buffer.get_pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::TXT {
+                ref domain,
+                ref data,
+                class,
                 ttl,
             } => {
-                buffer.write_qname(domain);
-                buffer.write_u16(QueryType::MX.to_num());
-                buffer.write_u16(1);
-                buffer.write_u32(ttl);
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::TXT.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
 
                 let pos = buffer.get_pos();
-                buffer.write_u16(0);
-                buffer.write_u16(priority);
-                buffer.write_qname(host);
+                buffer.write_u16(0)?;
+
+                // Every character-string carries its own length byte
+                for string in data {
+                    if string.len() > 255 {
+                        return Err(DnsError::BadRdLength);
+                    }
+
+                    buffer.write_u8(string.len() as u8)?;
+                    buffer.write_bytes(string)?;
+                }
 
                 let size = buffer.get_pos() - (pos + 2);
-                buffer.set_u16(pos, size as u16);
+                buffer.set_u16(pos, size as u16)?;
             },
             DnsRecord::AAAA {
                 ref domain,
                 ref addr,
+                class,
                 ttl,
             } => {
-                buffer.write_qname(domain);
-                buffer.write_u16(QueryType::AAAA.to_num());
-                buffer.write_u16(1);
-                buffer.write_u32(ttl);
-                buffer.write_u16(16);
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::AAAA.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(16)?;
 
                 for octet in &addr.segments() {
-                    buffer.write_u16(*octet);
+                    buffer.write_u16(*octet)?;
                 }
             },
-            DnsRecord::UNKNOWN { .. } => {
-                println!("Skipping Record: {:?}", self);
+            DnsRecord::SRV {
+                ref domain,
+                priority,
+                weight,
+                port,
+                ref host,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(QueryType::SRV.to_num())?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+
+                let pos = buffer.get_pos();
+                buffer.write_u16(0)?;
+                buffer.write_u16(priority)?;
+                buffer.write_u16(weight)?;
+                buffer.write_u16(port)?;
+                buffer.write_uncompressed_qname(host)?;
+
+                let size = buffer.get_pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::OPT {
+                payload_size,
+                extended_rcode,
+                version,
+                dnssec_ok,
+                ref options,
+            } => {
+                buffer.write_qname("")?;
+                buffer.write_u16(QueryType::OPT.to_num())?;
+                buffer.write_u16(payload_size)?;
+                buffer.write_u8(extended_rcode)?;
+                buffer.write_u8(version)?;
+                buffer.write_u16((dnssec_ok as u16) << 15)?;
+
+                let pos = buffer.get_pos();
+                buffer.write_u16(0)?;
+
+                for option in options {
+                    option.write(buffer)?;
+                }
+
+                let size = buffer.get_pos() - (pos + 2);
+                buffer.set_u16(pos, size as u16)?;
+            },
+            DnsRecord::UNKNOWN {
+                ref domain,
+                qtype,
+                ref data,
+                class,
+                ttl,
+            } => {
+                buffer.write_qname(domain)?;
+                buffer.write_u16(qtype)?;
+                buffer.write_u16(class.to_num())?;
+                buffer.write_u32(ttl)?;
+                buffer.write_u16(data.len() as u16)?;
+                buffer.write_bytes(data)?;
             }
         }
+
+        return Ok(());
+    }
+
+    pub fn get_domain(&self) -> &str {
+        match *self {
+            Self::UNKNOWN { ref domain, .. }
+            | Self::A { ref domain, .. }
+            | Self::NS { ref domain, .. }
+            | Self::CNAME { ref domain, .. }
+            | Self::SOA { ref domain, .. }
+            | Self::PTR { ref domain, .. }
+            | Self::MX { ref domain, .. }
+            | Self::TXT { ref domain, .. }
+            | Self::AAAA { ref domain, .. }
+            | Self::SRV { ref domain, .. } => domain,
+            Self::OPT { .. }               => "",
+        }
     }
-}
\ No newline at end of file
+
+    pub fn get_qtype(&self) -> QueryType {
+        match *self {
+            Self::UNKNOWN { qtype, .. } => QueryType::UNKNOWN(qtype),
+            Self::A { .. }              => QueryType::A,
+            Self::NS { .. }             => QueryType::NS,
+            Self::CNAME { .. }          => QueryType::CNAME,
+            Self::SOA { .. }            => QueryType::SOA,
+            Self::PTR { .. }            => QueryType::PTR,
+            Self::MX { .. }             => QueryType::MX,
+            Self::TXT { .. }            => QueryType::TXT,
+            Self::AAAA { .. }           => QueryType::AAAA,
+            Self::SRV { .. }            => QueryType::SRV,
+            Self::OPT { .. }            => QueryType::OPT,
+        }
+    }
+
+    pub fn get_class(&self) -> DnsClass {
+        match *self {
+            Self::UNKNOWN { class, .. }
+            | Self::A { class, .. }
+            | Self::NS { class, .. }
+            | Self::CNAME { class, .. }
+            | Self::SOA { class, .. }
+            | Self::PTR { class, .. }
+            | Self::MX { class, .. }
+            | Self::TXT { class, .. }
+            | Self::AAAA { class, .. }
+            | Self::SRV { class, .. } => class,
+            Self::OPT { payload_size, .. } => DnsClass::UNKNOWN(payload_size),
+        }
+    }
+
+    pub fn get_ttl(&self) -> u32 {
+        match *self {
+            Self::UNKNOWN { ttl, .. }
+            | Self::A { ttl, .. }
+            | Self::NS { ttl, .. }
+            | Self::CNAME { ttl, .. }
+            | Self::SOA { ttl, .. }
+            | Self::PTR { ttl, .. }
+            | Self::MX { ttl, .. }
+            | Self::TXT { ttl, .. }
+            | Self::AAAA { ttl, .. }
+            | Self::SRV { ttl, .. } => ttl,
+            Self::OPT { .. }        => 0,
+        }
+    }
+
+    pub fn set_ttl(&mut self, new_ttl: u32) {
+        match *self {
+            Self::UNKNOWN { ref mut ttl, .. }
+            | Self::A { ref mut ttl, .. }
+            | Self::NS { ref mut ttl, .. }
+            | Self::CNAME { ref mut ttl, .. }
+            | Self::SOA { ref mut ttl, .. }
+            | Self::PTR { ref mut ttl, .. }
+            | Self::MX { ref mut ttl, .. }
+            | Self::TXT { ref mut ttl, .. }
+            | Self::AAAA { ref mut ttl, .. }
+            | Self::SRV { ref mut ttl, .. } => *ttl = new_ttl,
+            Self::OPT { .. }                => (),
+        }
+    }
+
+    pub fn set_domain(&mut self, new_domain: &str) {
+        match *self {
+            Self::UNKNOWN { ref mut domain, .. }
+            | Self::A { ref mut domain, .. }
+            | Self::NS { ref mut domain, .. }
+            | Self::CNAME { ref mut domain, .. }
+            | Self::SOA { ref mut domain, .. }
+            | Self::PTR { ref mut domain, .. }
+            | Self::MX { ref mut domain, .. }
+            | Self::TXT { ref mut domain, .. }
+            | Self::AAAA { ref mut domain, .. }
+            | Self::SRV { ref mut domain, .. } => *domain = new_domain.to_string(),
+            Self::OPT { .. }                   => (),
+        }
+    }
+}
+
+// Presentation format as used in master files (RFC 1035 5.1), with unknown types in
+// the generic TYPE12345 \# form (RFC 3597 5)
+impl fmt::Display for DnsRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Self::OPT { payload_size, extended_rcode, version, dnssec_ok, ref options } = *self {
+            return write!(f, "; EDNS: version {}, do {}, rcode {}, udp {}, options {:?}",
+                          version, dnssec_ok, extended_rcode, payload_size, options);
+        }
+
+        write!(f, "{} {} {} {} ", fqdn(self.get_domain()), self.get_ttl(), self.get_class(), self.get_qtype())?;
+
+        match *self {
+            Self::A { ref addr, .. } => write!(f, "{}", addr),
+            Self::NS { ref host, .. }
+            | Self::CNAME { ref host, .. }
+            | Self::PTR { ref host, .. } => write!(f, "{}", fqdn(host)),
+            Self::SOA { ref mname, ref rname, serial, refresh, retry, expire, minimum, .. } => {
+                write!(f, "{} {} {} {} {} {} {}", fqdn(mname), fqdn(rname), serial, refresh, retry, expire, minimum)
+            },
+            Self::MX { priority, ref host, .. } => write!(f, "{} {}", priority, fqdn(host)),
+            Self::TXT { ref data, .. } => {
+                let strings: Vec<String> = data.iter().map(|string| quote(string)).collect();
+                write!(f, "{}", strings.join(" "))
+            },
+            Self::AAAA { ref addr, .. } => write!(f, "{}", addr),
+            Self::SRV { priority, weight, port, ref host, .. } => {
+                write!(f, "{} {} {} {}", priority, weight, port, fqdn(host))
+            },
+            Self::UNKNOWN { ref data, .. } => {
+                write!(f, "\\# {}", data.len())?;
+                if !data.is_empty() {
+                    let hex: String = data.iter().map(|byte| format!("{:02x}", byte)).collect();
+                    write!(f, " {}", hex)?;
+                }
+
+                Ok(())
+            },
+            Self::OPT { .. } => Ok(()),
+        }
+    }
+}
+
+// Names are stored without the trailing dot, the root being the empty string
+pub fn fqdn(name: &str) -> String {
+    return format!("{}.", name);
+}
+
+// A character-string in double quotes, with anything unprintable written as \DDD
+fn quote(string: &[u8]) -> String {
+    let mut result = String::from("\"");
+    for &byte in string {
+        match byte {
+            b'"' | b'\\' => {
+                result.push('\\');
+                result.push(byte as char);
+            },
+            0x20..=0x7E => result.push(byte as char),
+            _           => result.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+
+    result.push('"');
+    return result;
+}
@@ -1,22 +1,56 @@
 #[derive(Copy, Clone, Debug)]
 pub enum ResultCode {
-    NOERROR  = 0,
-    FORMERR  = 1,
-    SERVFAIL = 2,
-    NXDOMAIN = 3,
-    NOTIMP   = 4,
-    REFUSED  = 5,
+    UNKNOWN(u16),
+    NOERROR,
+    FORMERR,
+    SERVFAIL,
+    NXDOMAIN,
+    NOTIMP,
+    REFUSED,
+    YXDOMAIN,
+    YXRRSET,
+    NXRRSET,
+    NOTAUTH,
+    NOTZONE,
+    BADVERS,
 }
 
 impl ResultCode {
-    pub fn from_num(num: u8) -> Self {
+    pub fn to_num(self) -> u16 {
+        match self {
+            Self::UNKNOWN(x) => x,
+            Self::NOERROR    => 0,
+            Self::FORMERR    => 1,
+            Self::SERVFAIL   => 2,
+            Self::NXDOMAIN   => 3,
+            Self::NOTIMP     => 4,
+            Self::REFUSED    => 5,
+            Self::YXDOMAIN   => 6,
+            Self::YXRRSET    => 7,
+            Self::NXRRSET    => 8,
+            Self::NOTAUTH    => 9,
+            Self::NOTZONE    => 10,
+            Self::BADVERS    => 16,
+        }
+    }
+
+    // Takes the full 12 bit code, with the upper 8 bits coming from an OPT record.
+    // Codes we don't know are kept as they are, never mistaken for success.
+    pub fn from_num(num: u16) -> Self {
         match num {
-            1     => ResultCode::FORMERR,
-            2     => ResultCode::SERVFAIL,
-            3     => ResultCode::NXDOMAIN,
-            4     => ResultCode::NOTIMP,
-            5     => ResultCode::REFUSED,
-            0 | _ => ResultCode::NOERROR,
+            0  => Self::NOERROR,
+            1  => Self::FORMERR,
+            2  => Self::SERVFAIL,
+            3  => Self::NXDOMAIN,
+            4  => Self::NOTIMP,
+            5  => Self::REFUSED,
+            6  => Self::YXDOMAIN,
+            7  => Self::YXRRSET,
+            8  => Self::NXRRSET,
+            9  => Self::NOTAUTH,
+            10 => Self::NOTZONE,
+            16 => Self::BADVERS,
+            _  => Self::UNKNOWN(num),
         }
     }
-}
\ No newline at end of file
+}
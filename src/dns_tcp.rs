@@ -0,0 +1,30 @@
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use crate::packet_buffer::PacketBuffer;
+
+// DNS over TCP prefixes every message with its length as a 2 byte integer (RFC 7766)
+
+// Reads one framed message. Returns None when the peer closed the connection cleanly
+// between messages.
+pub fn read_message(stream: &mut TcpStream) -> io::Result<Option<PacketBuffer>> {
+    let mut len_bytes = [0; 2];
+    match stream.read_exact(&mut len_bytes) {
+        Ok(())                                                 => (),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err)                                               => return Err(err),
+    }
+
+    let mut message = vec![0; u16::from_be_bytes(len_bytes) as usize];
+    stream.read_exact(&mut message)?;
+
+    return Ok(Some(PacketBuffer::from_bytes(&message)));
+}
+
+pub fn write_message(stream: &mut TcpStream, data: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(data.len() + 2);
+    message.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    message.extend_from_slice(data);
+
+    return stream.write_all(&message);
+}
@@ -0,0 +1,129 @@
+use crate::dns_error::DnsError;
+use crate::packet_buffer::PacketBuffer;
+
+// Options carried in the RDATA of an OPT record (RFC 6891 6.1.2)
+#[derive(Clone, Debug)]
+pub enum EdnsOption {
+    UNKNOWN {
+        code: u16,
+        data: Vec<u8>,
+    },
+    NSID(Vec<u8>), // 3
+    SUBNET {
+        family:        u16,
+        source_prefix: u8,
+        scope_prefix:  u8,
+        addr:          Vec<u8>,
+    }, // 8
+    COOKIE {
+        client: Vec<u8>,
+        server: Vec<u8>,
+    }, // 10
+    KEEPALIVE(Option<u16>), // 11
+    PADDING(u16), // 12
+}
+
+impl EdnsOption {
+    pub fn read(buffer: &mut PacketBuffer) -> Result<Self, DnsError> {
+        let code = buffer.read_u16()?;
+        let len  = buffer.read_u16()? as usize;
+
+        let option = match code {
+            3 => Self::NSID(buffer.read_bytes(len)?),
+            8 => {
+                if len < 4 {
+                    return Err(DnsError::BadRdLength);
+                }
+
+                Self::SUBNET {
+                    family: buffer.read_u16()?,
+                    source_prefix: buffer.read()?,
+                    scope_prefix: buffer.read()?,
+                    addr: buffer.read_bytes(len - 4)?,
+                }
+            },
+            10 => {
+                // 8 byte client cookie, optionally followed by an 8 to 32 byte server cookie
+                if len != 8 && !(16..=40).contains(&len) {
+                    return Err(DnsError::BadRdLength);
+                }
+
+                Self::COOKIE {
+                    client: buffer.read_bytes(8)?,
+                    server: buffer.read_bytes(len - 8)?,
+                }
+            },
+            11 => {
+                match len {
+                    0 => Self::KEEPALIVE(None),
+                    2 => Self::KEEPALIVE(Some(buffer.read_u16()?)),
+                    _ => return Err(DnsError::BadRdLength),
+                }
+            },
+            12 => {
+                buffer.step_pos(len)?;
+                Self::PADDING(len as u16)
+            },
+            _ => Self::UNKNOWN {
+                code: code,
+                data: buffer.read_bytes(len)?,
+            },
+        };
+
+        return Ok(option);
+    }
+
+    pub fn write(&self, buffer: &mut PacketBuffer) -> Result<(), DnsError> {
+        buffer.write_u16(self.get_code())?;
+
+        let pos = buffer.get_pos();
+        buffer.write_u16(0)?;
+
+        match *self {
+            Self::UNKNOWN { ref data, .. } | Self::NSID(ref data) => {
+                buffer.write_bytes(data)?;
+            },
+            Self::SUBNET {
+                family,
+                source_prefix,
+                scope_prefix,
+                ref addr,
+            } => {
+                buffer.write_u16(family)?;
+                buffer.write_u8(source_prefix)?;
+                buffer.write_u8(scope_prefix)?;
+                buffer.write_bytes(addr)?;
+            },
+            Self::COOKIE { ref client, ref server } => {
+                buffer.write_bytes(client)?;
+                buffer.write_bytes(server)?;
+            },
+            Self::KEEPALIVE(timeout) => {
+                if let Some(timeout) = timeout {
+                    buffer.write_u16(timeout)?;
+                }
+            },
+            Self::PADDING(len) => {
+                for _ in 0..len {
+                    buffer.write_u8(0)?;
+                }
+            },
+        }
+
+        let size = buffer.get_pos() - (pos + 2);
+        buffer.set_u16(pos, size as u16)?;
+
+        return Ok(());
+    }
+
+    pub fn get_code(&self) -> u16 {
+        match *self {
+            Self::UNKNOWN { code, .. } => code,
+            Self::NSID(_)              => 3,
+            Self::SUBNET { .. }        => 8,
+            Self::COOKIE { .. }        => 10,
+            Self::KEEPALIVE(_)         => 11,
+            Self::PADDING(_)           => 12,
+        }
+    }
+}
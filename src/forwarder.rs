@@ -0,0 +1,254 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::dns_packet::DnsPacket;
+use crate::dns_query_type::QueryType;
+use crate::dns_result_code::ResultCode;
+use crate::resolver::{lookup, ResolverConfig};
+use crate::server_stats::ServerStats;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ForwardStrategy {
+    RoundRobin,
+    LowestLatency,
+}
+
+impl FromStr for ForwardStrategy {
+    type Err = String;
+
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        match strategy {
+            "round-robin"    => Ok(Self::RoundRobin),
+            "lowest-latency" => Ok(Self::LowestLatency),
+            _                => Err("expected round-robin or lowest-latency".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ForwardStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Self::RoundRobin    => write!(f, "round-robin"),
+            Self::LowestLatency => write!(f, "lowest-latency"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ZoneMode {
+    Forward,
+    Stub,
+}
+
+pub enum ZoneTarget {
+    Forward(Forwarder),    // Recursive resolvers, asked with RD set
+    Stub(Vec<SocketAddr>), // Authoritative servers for the zone, iterated from like a referral
+}
+
+// A conditional forwarding entry, covering the zone and every name below it
+pub struct ForwardZone {
+    pub name:   String,
+    pub target: ZoneTarget,
+}
+
+// The entry with the longest name that is qname or one of its parents
+pub fn find_zone<'a>(zones: &'a [ForwardZone], qname: &str) -> Option<&'a ForwardZone> {
+    let qname = qname.to_lowercase();
+
+    return zones.iter()
+                .filter(|zone| {
+                    zone.name.is_empty()
+                    || qname == zone.name
+                    || qname.ends_with(&format!(".{}", zone.name))
+                })
+                .max_by_key(|zone| zone.name.len());
+}
+
+// Hands questions to a fixed list of upstream resolvers with RD set instead of
+// resolving them from the roots. Upstreams that stop answering are skipped until a
+// health check sees them respond again.
+pub struct Forwarder {
+    upstreams: Vec<SocketAddr>,
+    strategy:  ForwardStrategy,
+    down:      Mutex<HashSet<SocketAddr>>, // Upstreams that stopped answering
+    stats:     Mutex<ServerStats>,         // Ranks the upstreams for lowest-latency
+    next:      AtomicUsize,                // Round-robin position
+}
+
+impl Forwarder {
+    pub fn new(upstreams: Vec<SocketAddr>, strategy: ForwardStrategy) -> Self {
+        Self {
+            upstreams: upstreams,
+            strategy:  strategy,
+            down:      Mutex::new(HashSet::new()),
+            stats:     Mutex::new(ServerStats::new()),
+            next:      AtomicUsize::new(0),
+        }
+    }
+
+    pub fn forward(&self, qname: &str, qtype: QueryType, config: &ResolverConfig) -> Result<DnsPacket, ()> {
+        for upstream in self.candidates() {
+            debug!("forwarding {} {} to {}", qtype, qname, upstream);
+
+            let started = Instant::now();
+            let result  = match lookup((upstream.ip(), upstream.port()), qname, qtype, true, config) {
+                Ok(result) => result,
+                Err(_)     => {
+                    warn!("Upstream {} is not responding, failing over", upstream);
+                    self.update(upstream, None);
+                    continue;
+                }
+            };
+
+            // It answered, so it's up, but another upstream may do better with the name
+            self.update(upstream, Some(started.elapsed()));
+            if matches!(result.header.response_code, ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::UNKNOWN(_)) {
+                warn!("Upstream {} answered {:?}, trying the next one", upstream, result.header.response_code);
+                continue;
+            }
+
+            return Ok(result);
+        }
+
+        return Err(());
+    }
+
+    // Asks every upstream for the root NS set. Any reply at all counts as healthy.
+    pub fn check_health(&self, config: &ResolverConfig) {
+        for upstream in &self.upstreams {
+            let started = Instant::now();
+            let rtt     = lookup((upstream.ip(), upstream.port()), "", QueryType::NS, true, config)
+                             .ok()
+                             .map(|_| started.elapsed());
+
+            self.update(*upstream, rtt);
+        }
+    }
+
+    // Order to try the upstreams in: the healthy ones as the strategy ranks them, then
+    // the rest in case they recovered since the last health check
+    fn candidates(&self) -> Vec<SocketAddr> {
+        let (mut healthy, unhealthy): (Vec<SocketAddr>, Vec<SocketAddr>) = {
+            let down = self.down.lock().unwrap();
+            self.upstreams.iter().partition(|upstream| !down.contains(upstream))
+        };
+
+        match self.strategy {
+            ForwardStrategy::RoundRobin => {
+                if !healthy.is_empty() {
+                    let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                    healthy.rotate_left(start);
+                }
+            },
+            ForwardStrategy::LowestLatency => {
+                healthy = self.stats.lock().unwrap().rank(&healthy);
+            },
+        }
+
+        healthy.extend(unhealthy);
+        return healthy;
+    }
+
+    // Records a reply that took `rtt`, or no reply at all
+    fn update(&self, upstream: SocketAddr, rtt: Option<Duration>) {
+        let mut down = self.down.lock().unwrap();
+
+        match rtt {
+            Some(rtt) => {
+                if down.remove(&upstream) {
+                    info!("Upstream {} is responding again", upstream);
+                }
+
+                self.stats.lock().unwrap().record_rtt(upstream, rtt);
+            },
+            None => {
+                if down.insert(upstream) {
+                    warn!("Marking upstream {} as down", upstream);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::resolver::tests::{a, config, upstream};
+
+    // An upstream that answers every question with the response code
+    fn answering(response_code: ResultCode) -> SocketAddr {
+        return upstream(move |question| {
+            let mut result              = DnsPacket::new();
+            result.header.response_code = response_code;
+            if matches!(response_code, ResultCode::NOERROR) {
+                result.answer_section.push(a(&question.qname, 1));
+            }
+
+            Some(result)
+        });
+    }
+
+    #[test]
+    fn failover_on_errors() {
+        let good   = answering(ResultCode::NOERROR);
+        let config = config(good);
+
+        for response_code in [ResultCode::SERVFAIL, ResultCode::REFUSED, ResultCode::UNKNOWN(11)] {
+            let bad       = answering(response_code);
+            let forwarder = Forwarder::new(vec![bad, good], ForwardStrategy::RoundRobin);
+
+            let result = forwarder.forward("www.test", QueryType::A, &config).unwrap();
+            assert_eq!(result.answer_section[0].to_string(), "www.test. 300 IN A 192.0.2.1");
+
+            // It answered, only not usefully, so it isn't down
+            assert!(forwarder.down.lock().unwrap().is_empty());
+
+            // With nobody else to ask, the error is ours
+            let alone = Forwarder::new(vec![bad], ForwardStrategy::RoundRobin);
+            assert!(alone.forward("www.test", QueryType::A, &config).is_err());
+        }
+
+        // An answer that is an error in its own right is passed on
+        let nxdomain  = answering(ResultCode::NXDOMAIN);
+        let forwarder = Forwarder::new(vec![nxdomain, good], ForwardStrategy::RoundRobin);
+        assert!(matches!(forwarder.forward("www.test", QueryType::A, &config).unwrap().header.response_code, ResultCode::NXDOMAIN));
+    }
+
+    #[test]
+    fn down_upstream_comes_back_after_a_health_check() {
+        let up    = Arc::new(AtomicBool::new(false));
+        let flaky = {
+            let up = Arc::clone(&up);
+            upstream(move |_| up.load(Ordering::SeqCst).then(DnsPacket::new))
+        };
+
+        let steady    = answering(ResultCode::NOERROR);
+        let config    = config(steady);
+        let forwarder = Forwarder::new(vec![flaky, steady], ForwardStrategy::RoundRobin);
+
+        assert!(forwarder.forward("www.test", QueryType::A, &config).is_ok());
+        assert!(forwarder.down.lock().unwrap().contains(&flaky));
+        assert_eq!(forwarder.candidates(), [steady, flaky]);
+
+        // Still silent, still down
+        forwarder.check_health(&config);
+        assert!(forwarder.down.lock().unwrap().contains(&flaky));
+
+        up.store(true, Ordering::SeqCst);
+        forwarder.check_health(&config);
+        assert!(forwarder.down.lock().unwrap().is_empty());
+        assert_eq!(forwarder.candidates().len(), 2);
+    }
+}
@@ -0,0 +1,402 @@
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::dns_query_type::QueryType;
+use crate::dns_record::DnsRecord;
+use crate::master_file;
+use crate::zone::{serial_newer, Zone};
+
+// Oldest changes are dropped past this many, clients further behind get a full transfer
+const MAX_DIFFS: usize = 100;
+
+// One change to a zone in IXFR order (RFC 1995 4): `deleted` starts with the old SOA
+// and `added` with the new one
+#[derive(Clone)]
+pub struct Diff {
+    pub deleted: Vec<DnsRecord>,
+    pub added:   Vec<DnsRecord>,
+}
+
+impl Diff {
+    // What turns `old` into `new`, starting with their SOAs even when those are the
+    // same. Records are compared in presentation format, so a changed TTL is a deletion
+    // and an addition like in any IXFR.
+    pub fn between(old: &Zone, new: &Zone) -> Self {
+        let old_records: HashSet<String> = old.records().iter().map(|record| record.to_string()).collect();
+        let new_records: HashSet<String> = new.records().iter().map(|record| record.to_string()).collect();
+
+        // records() puts the SOA first
+        let mut deleted = vec![old.soa().clone()];
+        deleted.extend(old.records()
+                          .into_iter()
+                          .skip(1)
+                          .filter(|record| !new_records.contains(&record.to_string()))
+                          .cloned());
+
+        let mut added = vec![new.soa().clone()];
+        added.extend(new.records()
+                        .into_iter()
+                        .skip(1)
+                        .filter(|record| !old_records.contains(&record.to_string()))
+                        .cloned());
+
+        return Self {
+            deleted: deleted,
+            added:   added,
+        };
+    }
+
+    // Only a change from one SOA to another with a newer serial can be told apart from
+    // the ones around it, and be served over IXFR
+    pub fn is_valid(&self) -> bool {
+        let starts_with_soa = |records: &[DnsRecord]| records.first().is_some_and(|record| record.get_qtype() == QueryType::SOA);
+
+        return starts_with_soa(&self.deleted)
+               && starts_with_soa(&self.added)
+               && serial_newer(self.new_serial(), self.old_serial());
+    }
+
+    // Whether nothing but the SOA changed
+    pub fn only_soa(&self) -> bool {
+        return self.deleted.len() <= 1 && self.added.len() <= 1;
+    }
+
+    pub fn old_serial(&self) -> u32 {
+        return self.deleted.first().map_or(0, soa_serial);
+    }
+
+    pub fn new_serial(&self) -> u32 {
+        return self.added.first().map_or(0, soa_serial);
+    }
+
+    // Sequence of records as they go in an IXFR response or the journal file
+    pub fn records(&self) -> impl Iterator<Item = &DnsRecord> {
+        return self.deleted.iter().chain(self.added.iter());
+    }
+}
+
+// The changes made to a zone, newest last. Kept in memory for answering IXFR and,
+// when there is a path, appended to a file so they survive restarts. The file is a
+// master file of the diffs' records in IXFR order.
+pub struct Journal {
+    path:  Option<PathBuf>,
+    diffs: Vec<Diff>,
+}
+
+impl Journal {
+    pub fn in_memory() -> Self {
+        Self {
+            path:  None,
+            diffs: Vec::new(),
+        }
+    }
+
+    // Reads the journal at `path` if there is one. One that can't be read is
+    // discarded rather than refusing to serve the zone, since clients can still fall
+    // back to AXFR.
+    pub fn open(path: PathBuf, origin: &str) -> Self {
+        let mut journal = Self {
+            path:  Some(path.clone()),
+            diffs: Vec::new(),
+        };
+
+        if !path.exists() {
+            return journal;
+        }
+
+        match master_file::load(&path, origin).and_then(split_diffs) {
+            Ok(diffs) => journal.diffs = diffs,
+            Err(err)  => {
+                warn!("Discarding journal {}: {}", path.display(), err);
+                journal.reset();
+            },
+        }
+
+        return journal;
+    }
+
+    // Brings a zone loaded from its master file up to date with the changes journaled
+    // since. A journal that doesn't pick up from the file's serial belongs to an older
+    // version of the file and is started over.
+    pub fn replay(&mut self, zone: Zone) -> Zone {
+        let Some(last) = self.diffs.last() else {
+            return zone;
+        };
+
+        if last.new_serial() == zone.serial() {
+            return zone;
+        }
+
+        if let Some(diffs) = self.diffs_since(zone.serial(), last.new_serial()) {
+            match zone.apply(&diffs) {
+                Ok(replayed) => {
+                    info!("Replayed {} journaled changes to zone {}., now at serial {}", diffs.len(), zone.origin, replayed.serial());
+                    return replayed;
+                },
+                Err(err) => warn!("Could not replay the journal of zone {}.: {}", zone.origin, err),
+            }
+        }
+
+        warn!("Journal of zone {}. doesn't follow serial {} of its file, starting a new one", zone.origin, zone.serial());
+        self.reset();
+
+        return zone;
+    }
+
+    // A change that isn't valid breaks the chain from older serials, the journal is
+    // started over and clients further behind get a full transfer
+    pub fn append(&mut self, diff: Diff) {
+        if !diff.is_valid() {
+            warn!("Change from serial {} to {} can't be journaled, starting a new journal", diff.old_serial(), diff.new_serial());
+            self.reset();
+            return;
+        }
+
+        if let Some(ref path) = self.path {
+            if let Err(err) = append_file(path, &diff) {
+                warn!("Could not write journal {}: {}", path.display(), err);
+            }
+        }
+
+        self.diffs.push(diff);
+
+        if self.diffs.len() > MAX_DIFFS {
+            let excess = self.diffs.len() - MAX_DIFFS;
+            self.diffs.drain(..excess);
+            self.rewrite();
+        }
+    }
+
+    // Drops every change, for when the zone was replaced by something the journal
+    // doesn't lead to
+    pub fn reset(&mut self) {
+        self.diffs.clear();
+        self.rewrite();
+    }
+
+    // The chain of changes from serial `from` up to `to`, or None when the journal
+    // doesn't reach back that far
+    pub fn diffs_since(&self, from: u32, to: u32) -> Option<Vec<Diff>> {
+        let start = self.diffs.iter().position(|diff| diff.old_serial() == from)?;
+
+        let mut chain = Vec::new();
+        let mut next  = from;
+        for diff in &self.diffs[start..] {
+            if diff.old_serial() != next {
+                return None;
+            }
+
+            chain.push(diff.clone());
+            next = diff.new_serial();
+            if next == to {
+                return Some(chain);
+            }
+        }
+
+        return None;
+    }
+
+    fn rewrite(&self) {
+        let Some(ref path) = self.path else {
+            return;
+        };
+
+        let result = fs::write(path, "").and_then(|_| {
+            self.diffs.iter().try_for_each(|diff| append_file(path, diff))
+        });
+
+        if let Err(err) = result {
+            warn!("Could not write journal {}: {}", path.display(), err);
+        }
+    }
+}
+
+fn append_file(path: &Path, diff: &Diff) -> io::Result<()> {
+    let mut text = format!("; serial {} to {}\n", diff.old_serial(), diff.new_serial());
+    for record in diff.records() {
+        text.push_str(&format!("{}\n", record));
+    }
+
+    // One write per diff, so a crash leaves at worst a partial last diff
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    return file.write_all(text.as_bytes());
+}
+
+// Splits records in IXFR order back into diffs: SOA, deletions, SOA, additions
+fn split_diffs(records: Vec<DnsRecord>) -> Result<Vec<Diff>, String> {
+    let mut diffs: Vec<Diff> = Vec::new();
+    let mut adding           = false;
+
+    for record in records {
+        let is_soa = record.get_qtype() == QueryType::SOA;
+        if is_soa && (diffs.is_empty() || adding) {
+            diffs.push(Diff { deleted: vec![record], added: Vec::new() });
+            adding = false;
+            continue;
+        }
+
+        let Some(diff) = diffs.last_mut() else {
+            return Err("doesn't start with an SOA".to_string());
+        };
+
+        if is_soa {
+            adding = true;
+        }
+
+        match adding {
+            true  => diff.added.push(record),
+            false => diff.deleted.push(record),
+        }
+    }
+
+    // A crash while appending can leave the last diff without its new SOA
+    if diffs.last().is_some_and(|diff| diff.added.is_empty()) {
+        diffs.pop();
+    }
+
+    if let Some(diff) = diffs.iter().find(|diff| !diff.is_valid()) {
+        return Err(format!("change from serial {} to {} doesn't move the serial forward", diff.old_serial(), diff.new_serial()));
+    }
+
+    for pair in diffs.windows(2) {
+        if pair[0].new_serial() != pair[1].old_serial() {
+            return Err(format!("serial {} is followed by a change from {}", pair[0].new_serial(), pair[1].old_serial()));
+        }
+    }
+
+    return Ok(diffs);
+}
+
+pub fn soa_serial(record: &DnsRecord) -> u32 {
+    match *record {
+        DnsRecord::SOA { serial, .. } => serial,
+        _                             => 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A small zone at the serial with the records added, from a master file of its own
+    fn version(test: &str, serial: u32, records: &str) -> Zone {
+        let path = std::env::temp_dir().join(format!("journal_{}_{}_{}", test, serial, std::process::id()));
+        fs::write(&path, format!("$TTL 300\n@ SOA ns1 hostmaster {} 3600 600 86400 60\n@ NS ns1\n{}", serial, records)).unwrap();
+
+        let zone = Zone::load("example.com", &path);
+        fs::remove_file(&path).unwrap();
+
+        return zone.unwrap();
+    }
+
+    fn strings<'a>(records: impl IntoIterator<Item = &'a DnsRecord>) -> Vec<String> {
+        return records.into_iter().map(|record| record.to_string()).collect();
+    }
+
+    fn serials(diffs: &[Diff]) -> Vec<(u32, u32)> {
+        return diffs.iter().map(|diff| (diff.old_serial(), diff.new_serial())).collect();
+    }
+
+    // Serials 1 to 3, each changing the address of www
+    fn history(test: &str) -> (Vec<Zone>, Journal) {
+        let zones: Vec<Zone> = (1..=3).map(|serial| version(test, serial, &format!("www A 192.0.2.{}\n", serial))).collect();
+
+        let mut journal = Journal::in_memory();
+        for pair in zones.windows(2) {
+            journal.append(Diff::between(&pair[0], &pair[1]));
+        }
+
+        return (zones, journal);
+    }
+
+    #[test]
+    fn diff_between_versions() {
+        let old  = version("between", 1, "www A 192.0.2.1\nftp A 192.0.2.3\n");
+        let new  = version("between", 2, "www A 192.0.2.1\nwww 60 A 192.0.2.3\nftp 60 A 192.0.2.3\n");
+        let diff = Diff::between(&old, &new);
+
+        assert_eq!(strings(&diff.deleted), [
+            "example.com. 300 IN SOA ns1.example.com. hostmaster.example.com. 1 3600 600 86400 60",
+            "ftp.example.com. 300 IN A 192.0.2.3",
+        ]);
+        assert_eq!(strings(&diff.added), [
+            "example.com. 300 IN SOA ns1.example.com. hostmaster.example.com. 2 3600 600 86400 60",
+            "ftp.example.com. 60 IN A 192.0.2.3",
+            "www.example.com. 60 IN A 192.0.2.3",
+        ]);
+        assert!(diff.is_valid());
+        assert_eq!(old.apply(&[diff]).map(|zone| strings(zone.records())), Ok(strings(new.records())));
+
+        // Without a newer serial there's nothing to journal
+        assert!(Diff::between(&old, &old).only_soa());
+        assert!(!Diff::between(&old, &old).is_valid());
+        assert!(!Diff::between(&new, &old).is_valid());
+    }
+
+    #[test]
+    fn split_diffs_in_ixfr_order() {
+        let (zones, journal) = history("split");
+        let records: Vec<DnsRecord> = journal.diffs.iter().flat_map(Diff::records).cloned().collect();
+
+        let diffs = split_diffs(records.clone()).unwrap();
+        assert_eq!(serials(&diffs), [(1, 2), (2, 3)]);
+        assert_eq!(strings(diffs.iter().flat_map(Diff::records)), strings(&records));
+
+        // A diff cut short by a crash is dropped
+        assert_eq!(serials(&split_diffs(records[..5].to_vec()).unwrap()), [(1, 2)]);
+
+        assert!(split_diffs(records[1..].to_vec()).is_err());
+
+        // Serials have to go forward and follow on from each other
+        let backwards = Diff::between(&zones[1], &zones[0]);
+        assert!(split_diffs(backwards.records().cloned().collect()).is_err());
+
+        let gap = Diff::between(&zones[0], &zones[2]);
+        assert!(split_diffs(records.iter().chain(gap.records()).cloned().collect()).is_err());
+    }
+
+    #[test]
+    fn diffs_since_a_serial() {
+        let (_, journal) = history("since");
+
+        assert_eq!(journal.diffs_since(1, 3).as_deref().map(serials), Some(vec![(1, 2), (2, 3)]));
+        assert_eq!(journal.diffs_since(2, 3).as_deref().map(serials), Some(vec![(2, 3)]));
+        assert_eq!(journal.diffs_since(1, 2).as_deref().map(serials), Some(vec![(1, 2)]));
+        assert!(journal.diffs_since(0, 3).is_none());
+        assert!(journal.diffs_since(1, 4).is_none());
+        assert!(journal.diffs_since(3, 3).is_none());
+    }
+
+    #[test]
+    fn replay_from_the_file() {
+        let (zones, journal) = history("replay");
+        let path = std::env::temp_dir().join(format!("journal_replay_{}.jnl", std::process::id()));
+        fs::write(&path, "").unwrap();
+
+        let mut file = Journal::open(path.clone(), "example.com");
+        for diff in journal.diffs {
+            file.append(diff);
+        }
+
+        // What was written is read back and brings the file's version up to date
+        let mut journal = Journal::open(path.clone(), "example.com");
+        assert_eq!(strings(journal.replay(zones[0].clone()).records()), strings(zones[2].records()));
+        assert_eq!(strings(journal.replay(zones[1].clone()).records()), strings(zones[2].records()));
+        assert_eq!(strings(journal.replay(zones[2].clone()).records()), strings(zones[2].records()));
+        assert_eq!(journal.diffs.len(), 2);
+
+        // A file the journal doesn't follow on from starts it over
+        let unrelated = version("replay", 7, "");
+        assert_eq!(journal.replay(unrelated).serial(), 7);
+        assert!(journal.diffs.is_empty());
+        assert!(Journal::open(path.clone(), "example.com").diffs.is_empty());
+
+        // So does one that can't be read
+        fs::write(&path, "www A 192.0.2.1\n").unwrap();
+        assert!(Journal::open(path.clone(), "example.com").diffs.is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,121 +1,694 @@
+#![allow(clippy::needless_return, clippy::redundant_field_names, clippy::upper_case_acronyms)]
+
+#[macro_use]
+mod logger;
 mod packet_buffer;
 mod dns_packet;
+mod dns_error;
 mod dns_header;
 mod dns_question;
 mod dns_record;
 mod dns_query_type;
 mod dns_result_code;
 mod named_root;
+mod dns_cache;
+mod dns_class;
+mod dns_chaos;
+mod dns_tcp;
+mod edns_option;
+mod server_stats;
+mod resolver;
+mod config;
+mod forwarder;
+mod master_file;
+mod journal;
+mod notify;
+mod update;
+mod tsig;
+mod zone;
+mod zone_transfer;
 
-use std::net::UdpSocket;
+use std::collections::HashMap;
+use std::env;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, SystemTime};
+use config::Config;
+use forwarder::{ForwardZone, Forwarder, ZoneMode, ZoneTarget};
+use dns_class::DnsClass;
 use dns_packet::DnsPacket;
-use dns_record::DnsRecord;
 use dns_query_type::QueryType;
-use packet_buffer::PacketBuffer;
+use dns_record::DnsRecord;
+use dns_error::DnsError;
+use packet_buffer::{PacketBuffer, MAX_PACKET_SIZE, UDP_PACKET_SIZE};
 use dns_result_code::ResultCode;
 use named_root::NamedRoot;
+use resolver::{edns_record, Resolver, ResolverConfig};
+use tsig::{Key, Session};
+use journal::Journal;
+use zone::{Zone, ZoneStore};
+use config::ZoneSection;
+
+// How often the master files of primary zones are checked for edits
+const ZONE_FILE_POLL: Duration = Duration::from_secs(5);
 
 fn main() {
-    // "0.0.0.0" Stands for local address
-    let socket = UdpSocket::bind(("0.0.0.0", 8888)).unwrap();
-    let named_root         = NamedRoot::get_named_root();
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config            = Config::from_args(&args).unwrap_or_else(|err| exit_with_error(err));
+    logger::set_level(config.log_level);
+
+    let root_servers = match config.uses_root_hints() {
+        true  => get_root_servers(&config.resolver.root_hints).unwrap_or_else(|err| exit_with_error(err)),
+        false => Vec::new(),
+    };
+
+    let forwarder = match config.modes.forward {
+        true  => {
+            info!("Forwarding to {} upstreams, {}", config.forwarder.upstreams.len(), config.forwarder.strategy);
+            Some(Forwarder::new(config.forwarder.upstreams.clone(), config.forwarder.strategy))
+        },
+        false => None,
+    };
+
+    let forward_zones = config.forward_zones.iter().map(|zone| {
+        info!("Sending names under {:?} to {:?} as {:?}", zone.name, zone.servers, zone.mode);
+
+        ForwardZone {
+            name:   zone.name.clone(),
+            target: match zone.mode {
+                ZoneMode::Forward => ZoneTarget::Forward(Forwarder::new(zone.servers.clone(), config.forwarder.strategy)),
+                ZoneMode::Stub    => ZoneTarget::Stub(zone.servers.clone()),
+            },
+        }
+    }).collect();
+
+    let resolver_config = ResolverConfig {
+        root_servers:    root_servers,
+        upstream_port:   config.resolver.upstream_port,
+        timeout:         Duration::from_millis(config.resolver.timeout_ms),
+        retries:         config.resolver.retries,
+        recursion_limit: config.resolver.recursion_limit,
+        max_outstanding: config.resolver.max_outstanding,
+        cache_size:      config.cache.max_entries,
+    };
+
+    let resolver = Arc::new(Resolver::new(resolver_config.clone(), forwarder, forward_zones));
+
+    let has_forward_zones = config.forward_zones.iter().any(|zone| zone.mode == ZoneMode::Forward);
+    if config.modes.forward || has_forward_zones {
+        let resolver = Arc::clone(&resolver);
+        let interval = Duration::from_millis(config.forwarder.health_check_ms);
+        thread::spawn(move || loop {
+            resolver.check_upstreams();
+            thread::sleep(interval);
+        });
+    }
+
+    let zones = Arc::new(ZoneStore::new());
+    let mut files = load_zones(&config, &zones).unwrap_or_else(|err| exit_with_error(err));
+
+    for section in &config.zones {
+        let zones  = Arc::clone(&zones);
+        let origin = section.name.clone();
+        let notify = section.notify.clone();
+        let key    = config.zone_key(section).cloned();
+        match (&section.file, section.primary) {
+            (_, Some(primary)) => {
+                let config = resolver_config.clone();
+                thread::spawn(move || zone_transfer::run_secondary(zones, origin, primary, notify, key, config));
+            },
+            (Some(file), None) => {
+                let file      = file.clone();
+                let file_zone = files.remove(&origin).unwrap();
+                let timeout   = resolver_config.timeout;
+                thread::spawn(move || watch_zone_file(zones, origin, file, file_zone, notify, key, timeout));
+            },
+            (None, None) => (),
+        }
+    }
+
+    let config      = Arc::new(config);
+    let connections = Arc::new(AtomicUsize::new(0));
+    let mut threads = Vec::new();
+
+    for addr in &config.listen {
+        let socket   = UdpSocket::bind(addr)
+                                 .unwrap_or_else(|err| exit_with_error(format!("could not listen on UDP {}: {}", addr, err)));
+        let listener = TcpListener::bind(addr)
+                                   .unwrap_or_else(|err| exit_with_error(format!("could not listen on TCP {}: {}", addr, err)));
+
+        info!("Listening on {}", addr);
+
+        // Each connection gets a thread of its own, up to the limit. Past it connections
+        // are closed right away, clients then retry or move on to another server.
+        {
+            let config      = Arc::clone(&config);
+            let resolver    = Arc::clone(&resolver);
+            let zones       = Arc::clone(&zones);
+            let connections = Arc::clone(&connections);
+            threads.push(thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let max      = config.tcp_connections;
+                    let admitted = connections.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < max).then_some(count + 1))
+                                              .is_ok();
+
+                    if !admitted {
+                        warn!("Already serving {} TCP connections, closing a new one", max);
+                        continue;
+                    }
+
+                    let config      = Arc::clone(&config);
+                    let resolver    = Arc::clone(&resolver);
+                    let zones       = Arc::clone(&zones);
+                    let connections = Arc::clone(&connections);
+                    thread::spawn(move || {
+                        handle_tcp_connection(&config, &resolver, &zones, stream);
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+            }));
+        }
+
+        // Every worker blocks on its own handle to the same socket, the OS hands each
+        // datagram to one of them. There should be more workers than the outstanding
+        // recursion limit, so some are always free to answer from the cache.
+        for _ in 0..config.workers {
+            let socket   = socket.try_clone().unwrap();
+            let config   = Arc::clone(&config);
+            let resolver = Arc::clone(&resolver);
+            let zones    = Arc::clone(&zones);
+            threads.push(thread::spawn(move || loop {
+                handle_query(&config, &resolver, &zones, &socket);
+            }));
+        }
+    }
+
+    for thread in threads {
+        let _ = thread.join();
+    }
+}
+
+fn exit_with_error(err: impl fmt::Display) -> ! {
+    eprintln!("dns_server: {}", err);
+    process::exit(1);
+}
+
+// Addresses of every root server in the hints file, IPv4 and IPv6 alike
+fn get_root_servers(path: &Path) -> Result<Vec<IpAddr>, String> {
+    let named_roots = NamedRoot::get_named_roots(path)
+                                .map_err(|err| format!("could not read root hints {}: {}", path.display(), err))?;
+
+    let root_servers: Vec<IpAddr> = named_roots.iter()
+                                               .flat_map(|named_root| named_root.get_addrs())
+                                               .collect();
 
+    if root_servers.is_empty() {
+        return Err(format!("root hints {} list no root server addresses", path.display()));
+    }
+
+    info!("Loaded {} root server addresses", root_servers.len());
+    return Ok(root_servers);
+}
+
+// Every zone in the config, primaries loaded from their master files and secondaries
+// left empty until their first transfer. Returns the primary zones as their files have
+// them, before the journal is replayed.
+fn load_zones(config: &Config, zones: &ZoneStore) -> Result<HashMap<String, Zone>, String> {
+    let mut files = HashMap::new();
+
+    for section in &config.zones {
+        let file = match (&section.file, section.primary) {
+            (_, Some(primary)) => {
+                info!("Serving zone {}. as a secondary of {}", section.name, primary);
+                zones.insert(&section.name, None, Journal::in_memory());
+                continue;
+            },
+            (Some(file), None) => file,
+            (None, None)       => continue, // Rejected by Config::validate()
+        };
+
+        let zone = Zone::load(&section.name, file)
+                       .map_err(|err| format!("could not load zone {}.: {}", section.name, err))?;
+
+        info!("Loaded zone {}. serial {} with {} records", section.name, zone.serial(), zone.record_count());
+
+        let mut journal = match section.journal_path() {
+            Some(path) => Journal::open(path, &section.name),
+            None       => Journal::in_memory(),
+        };
+
+        files.insert(section.name.clone(), zone.clone());
+
+        let zone = journal.replay(zone);
+        zones.insert(&section.name, Some(zone), journal);
+    }
+
+    return Ok(files);
+}
+
+// Reloads a primary zone whenever its master file changes. The change is journaled
+// for IXFR, so the new version has to come with a newer serial. `file_zone` is the
+// zone as the file had it the last time it was loaded, what the zone has on top of
+// that came from dynamic updates and is carried over to the new version.
+fn watch_zone_file(zones: Arc<ZoneStore>, origin: String, file: PathBuf, mut file_zone: Zone, notify: Vec<SocketAddr>, key: Option<Key>, timeout: Duration) {
+    let modified = |file: &Path| fs::metadata(file).and_then(|metadata| metadata.modified()).ok();
+
+    let mut last_modified: Option<SystemTime> = modified(&file);
     loop {
-        handle_query(&named_root.ipv4, &socket);
+        thread::sleep(ZONE_FILE_POLL);
+
+        let current = modified(&file);
+        if current.is_none() || current == last_modified {
+            continue;
+        }
+
+        last_modified = current;
+
+        let loaded = match Zone::load(&origin, &file) {
+            Ok(zone) => zone,
+            Err(err) => {
+                warn!("Could not reload zone {}.: {}", origin, err);
+                continue;
+            },
+        };
+
+        match zones.reload(&file_zone, &loaded) {
+            Ok(zone) => {
+                info!("Reloaded zone {}. serial {} with {} records", origin, zone.serial(), zone.record_count());
+                file_zone = loaded;
+                notify::send_notify(&zone, &notify, key.as_ref(), timeout);
+            },
+            Err(err) => error!("Not reloading zone {}. from {}: {}", origin, file.display(), err),
+        }
     }
 }
 
-fn handle_query(named_root_addr: &str, socket: &UdpSocket) {
-    let mut request_buffer = PacketBuffer::new();
-    let (_, src)           = socket.recv_from(&mut request_buffer.buff).unwrap();
-    let mut request_packet = DnsPacket::get_packet_from_buffer(&mut request_buffer);
+fn handle_query(config: &Config, resolver: &Resolver, zones: &ZoneStore, socket: &UdpSocket) {
+    let mut data   = [0; MAX_PACKET_SIZE];
+    let (len, src) = match socket.recv_from(&mut data) {
+        Ok(received) => received,
+        Err(err)     => {
+            error!("Failed to receive query: {}", err);
+            return;
+        }
+    };
 
-    let mut response_packet                    = DnsPacket::new();
-    response_packet.header.packet_identifier   = request_packet.header.packet_identifier;
-    response_packet.header.recursion_desired   = true;
-    response_packet.header.recursion_available = true;
-    response_packet.header.query_response      = true;
+    let mut request_buffer = PacketBuffer::from_bytes(&data[..len]);
+    let request            = DnsPacket::get_packet_from_buffer(&mut request_buffer);
 
-    if let Some(question) = request_packet.question_section.pop() {
-        println!("Received Query: {:?}", question);
+    // Responses have to fit the client's EDNS buffer size, or 512 bytes without EDNS
+    let limit = match request {
+        Ok(ref request_packet) => request_packet.get_udp_payload_size(),
+        Err(_)                 => UDP_PACKET_SIZE,
+    };
 
-        if let Ok(result) = recursive_resolver(named_root_addr, &question.qname, question.qtype) {
-            response_packet.question_section.push(question);
-            response_packet.header.response_code = result.header.response_code;
+    if let Some(response) = respond(config, resolver, zones, request, &data[..len], src.ip(), limit) {
+        if let Err(err) = socket.send_to(&response, src) {
+            error!("Failed to send response to {}: {}", src, err);
+        }
+    }
+}
 
-            for answer in result.answer_section {
-                println!("Answer: {:?}", answer);
-                response_packet.answer_section.push(answer);
-            }
+// Serves queries from one TCP client until it closes the connection or goes idle
+fn handle_tcp_connection(config: &Config, resolver: &Resolver, zones: &ZoneStore, mut stream: TcpStream) {
+    let _    = stream.set_read_timeout(Some(Duration::from_secs(10)));
+    let peer = match stream.peer_addr() {
+        Ok(addr) => addr.ip(),
+        Err(_)   => return,
+    };
 
-            for authority in result.authority_section {
-                println!("Authority: {:?}", authority);
-                response_packet.authority_section.push(authority);
+    loop {
+        let mut request_buffer = match dns_tcp::read_message(&mut stream) {
+            Ok(Some(buffer)) => buffer,
+            Ok(None)         => return,
+            Err(err)         => {
+                debug!("TCP connection closed: {}", err);
+                return;
             }
+        };
 
-            for additional in result.additional_section {
-                println!("Addition: {:?}", additional);
-                response_packet.additional_section.push(additional);
+        let request = DnsPacket::get_packet_from_buffer(&mut request_buffer);
+
+        // Zone transfers stream their own responses
+        if let Ok(ref request_packet) = request {
+            if is_transfer(request_packet) {
+                if let Err(err) = handle_transfer(config, zones, request_packet, request_buffer.get_data(), &mut stream) {
+                    debug!("Zone transfer connection closed: {}", err);
+                    return;
+                }
+
+                continue;
             }
-        } else {
-            response_packet.header.response_code = ResultCode::SERVFAIL;
         }
-    } else {
-        response_packet.header.response_code = ResultCode::FORMERR;
+
+        let Some(response) = respond(config, resolver, zones, request, request_buffer.get_data(), peer, MAX_PACKET_SIZE) else {
+            return;
+        };
+
+        if dns_tcp::write_message(&mut stream, &response).is_err() {
+            return;
+        }
+    }
+}
+
+fn is_transfer(request: &DnsPacket) -> bool {
+    return !request.header.query_response
+           && request.header.operation_code == 0
+           && request.question_section.len() == 1
+           && [zone_transfer::AXFR, zone_transfer::IXFR].contains(&request.question_section[0].qtype)
+           && request.question_section[0].qclass == DnsClass::IN;
+}
+
+// Sends the zone, or for IXFR what changed since the client's serial, to a client
+// that is allowed to transfer it. Anyone else gets a single message with the error.
+fn handle_transfer(config: &Config, zones: &ZoneStore, request: &DnsPacket, raw_request: &[u8], stream: &mut TcpStream) -> io::Result<()> {
+    let question = &request.question_section[0];
+    let peer     = stream.peer_addr()?.ip().to_canonical();
+    let kind     = if question.qtype == zone_transfer::IXFR { "IXFR" } else { "AXFR" };
+
+    let mut session = match tsig::verify_request(raw_request, request, &config.keys) {
+        Ok(session)    => session,
+        Err(rejection) => return dns_tcp::write_message(stream, &reject_signature(request, rejection, peer)),
+    };
+
+    let key     = session.as_ref().map(Session::key_name);
+    let allowed = config.zones.iter().any(|zone| {
+        zone.name == question.qname && (zone.allow_transfer.contains(&peer) || is_signed_for(zone, key))
+    });
+
+    // IXFR says which version the client has with an SOA in the authority section
+    let client_serial = request.authority_section.iter().find_map(|record| match *record {
+        DnsRecord::SOA { serial, .. } => Some(serial),
+        _                             => None,
+    });
+
+    let response_code = match zones.changes_since(&question.qname, client_serial.unwrap_or(0)) {
+        None                                                     => ResultCode::NOTAUTH,
+        Some(_) if !allowed                                      => ResultCode::REFUSED,
+        Some((zone, _)) if question.qtype == zone_transfer::AXFR => {
+            return zone_transfer::send_axfr(stream, request, &zone, session.as_mut());
+        },
+        Some((zone, diffs)) => match client_serial {
+            Some(serial) => return zone_transfer::send_ixfr(stream, request, &zone, diffs, serial, session.as_mut()),
+            None         => ResultCode::FORMERR,
+        },
+    };
+
+    warn!("Refusing {} of {}. to {}: {:?}", kind, question.qname, peer, response_code);
+
+    let mut response_packet = error_response(request, response_code);
+    let response_buffer     = write_response(&mut response_packet, MAX_PACKET_SIZE);
+    match session {
+        Some(ref mut session) => return dns_tcp::write_message(stream, &session.sign(response_buffer.get_data())),
+        None                  => return dns_tcp::write_message(stream, response_buffer.get_data()),
+    }
+}
+
+// Builds the response as it goes on the wire. A request signed with one of our TSIG
+// keys gets a signed response, one whose signature doesn't check out only the error.
+fn respond(config: &Config, resolver: &Resolver, zones: &ZoneStore, request: Result<DnsPacket, DnsError>, raw_request: &[u8], peer: IpAddr, limit: usize) -> Option<Vec<u8>> {
+    let session = match request {
+        Ok(ref request_packet) => match tsig::verify_request(raw_request, request_packet, &config.keys) {
+            Ok(session)    => session,
+            Err(rejection) => return Some(reject_signature(request_packet, rejection, peer)),
+        },
+        Err(_) => None,
+    };
+
+    let key                 = session.as_ref().map(|session| session.key_name().to_string());
+    let mut response_packet = build_response(config, resolver, zones, request, raw_request, peer, key.as_deref())?;
+
+    match session {
+        Some(mut session) => {
+            let response_buffer = write_response(&mut response_packet, limit.saturating_sub(session.record_size()));
+            return Some(session.sign(response_buffer.get_data()));
+        },
+        None => return Some(write_response(&mut response_packet, limit).get_data().to_vec()),
     }
+}
+
+// A bad signature is answered with NOTAUTH and the TSIG error, and nothing else
+// from the request is acted on (RFC 8945 5.2)
+fn reject_signature(request: &DnsPacket, rejection: tsig::Rejection, peer: IpAddr) -> Vec<u8> {
+    warn!("Rejecting signed request from {}: {}", peer, rejection);
+
+    let mut response_packet = error_response(request, ResultCode::NOTAUTH);
+    let response_buffer     = write_response(&mut response_packet, UDP_PACKET_SIZE);
+    return rejection.sign(response_buffer.get_data());
+}
 
-    let mut response_buffer = PacketBuffer::new();
-    response_packet.write_packet_to_buffer(&mut response_buffer);
+fn error_response(request: &DnsPacket, response_code: ResultCode) -> DnsPacket {
+    let mut response_packet                  = DnsPacket::new();
+    response_packet.header.packet_identifier = request.header.packet_identifier;
+    response_packet.header.operation_code    = request.header.operation_code;
+    response_packet.header.query_response    = true;
+    response_packet.header.response_code     = response_code;
+    response_packet.question_section         = request.question_section.clone();
 
-    let data_len = response_buffer.get_pos();
-    let data     = response_buffer.get_range(0, data_len);
-    socket.send_to(data, src).unwrap();
+    return response_packet;
 }
 
-fn recursive_resolver(named_root_addr: &str, qname: &str, qtype: QueryType) -> Result<DnsPacket, ()> {
-    let mut recursive_addr    = named_root_addr.to_string();
+// Whether the request was signed with the key the zone grants transfers, updates
+// and NOTIFYs to
+fn is_signed_for(zone: &ZoneSection, key: Option<&str>) -> bool {
+    return zone.key.is_some() && zone.key.as_deref() == key;
+}
+
+// Writes the response within `limit` bytes, dropping every record and setting TC if
+// it doesn't fit so the client knows to retry over TCP
+fn write_response(response_packet: &mut DnsPacket, limit: usize) -> PacketBuffer {
+    let mut response_buffer = PacketBuffer::with_limit(limit);
+    if let Err(err) = response_packet.write_packet_to_buffer(&mut response_buffer) {
+        debug!("Truncating response: {}", err);
+
+        response_packet.header.truncated_message = true;
+        response_packet.answer_section.clear();
+        response_packet.authority_section.clear();
+        response_packet.additional_section.retain(|record| matches!(record, DnsRecord::OPT { .. }));
+
+        response_buffer = PacketBuffer::with_limit(limit);
+        if response_packet.write_packet_to_buffer(&mut response_buffer).is_err() {
+            // Even the question doesn't fit, answer with the bare header
+            response_packet.question_section.clear();
+
+            response_buffer = PacketBuffer::with_limit(limit);
+            let _ = response_packet.write_packet_to_buffer(&mut response_buffer);
+        }
+    }
+
+    return response_buffer;
+}
+
+// Returns None when the request is too broken to even carry an ID worth answering
+fn build_response(config: &Config, resolver: &Resolver, zones: &ZoneStore, request: Result<DnsPacket, DnsError>, raw_request: &[u8], peer: IpAddr, key: Option<&str>) -> Option<DnsPacket> {
+    let mut response_packet                    = DnsPacket::new();
+    response_packet.header.recursion_desired   = true;
+    response_packet.header.recursion_available = config.modes.recursive || config.modes.forward;
+    response_packet.header.query_response      = true;
+
+    match request {
+        Ok(mut request_packet) => {
+            response_packet.header.packet_identifier = request_packet.header.packet_identifier;
 
-    for _ in 1..=100 { // Recursion Limit
-        println!("attempting lookup of {:?} {} with ns {}", qtype, qname, recursive_addr);
+            // Clients only understand an OPT in the response if they sent one
+            let mut dnssec_ok = false;
+            if let Some(&DnsRecord::OPT { version, dnssec_ok: client_dnssec_ok, .. }) = request_packet.get_opt() {
+                dnssec_ok = client_dnssec_ok;
+                response_packet.additional_section.push(edns_record(dnssec_ok));
 
-        let server: (&str, u16) = (&recursive_addr, 53);
-        if let Ok(result)       = lookup(server, qname, qtype) {
+                // Version 0 is the only one there is
+                if version > 0 {
+                    response_packet.header.response_code = ResultCode::BADVERS;
+                    return Some(response_packet);
+                }
+            }
 
-            if result.header.answer_count > 0 {
-                return Ok(result);
+            // Besides queries there are NOTIFYs and dynamic updates to take care of
+            response_packet.header.operation_code = request_packet.header.operation_code;
+            match request_packet.header.operation_code {
+                0                     => (),
+                notify::OPCODE_NOTIFY => {
+                    response_packet.header.response_code        = handle_notify(config, zones, &request_packet, peer, key);
+                    response_packet.header.authoritative_answer = true;
+                    response_packet.question_section            = request_packet.question_section;
+                    return Some(response_packet);
+                },
+                update::OPCODE_UPDATE => {
+                    response_packet.header.response_code = handle_update(config, zones, &request_packet, peer, key);
+                    response_packet.question_section     = request_packet.question_section;
+                    return Some(response_packet);
+                },
+                _ => {
+                    response_packet.header.response_code = ResultCode::NOTIMP;
+                    return Some(response_packet);
+                },
             }
 
-            for additional in result.additional_section {
-                match additional {
-                    DnsRecord::A {addr, ..} => {
-                        recursive_addr = addr.to_string();
+            if let Some(question) = request_packet.question_section.pop() {
+                info!("Received Query: {}", question);
+                let qtype = question.qtype;
+
+                // Our own zones come first, the rest of IN is left to recursion
+                let result = match question.qclass {
+                    // Transfers only work over TCP, which takes them before they get here
+                    _ if qtype == zone_transfer::AXFR => Ok(refused()),
+                    // An IXFR that doesn't fit gets our SOA, telling the client to come back over TCP (RFC 1995 2)
+                    DnsClass::IN if qtype == zone_transfer::IXFR => Ok(zones.answer(&question.qname, QueryType::SOA)
+                                                                            .unwrap_or_else(refused)),
+                    DnsClass::IN => match zones.answer(&question.qname, qtype) {
+                        Some(result) => Ok(result),
+                        None if config.modes.recursive || config.modes.forward => {
+                            // Whatever upstream said, a recursive answer isn't authoritative
+                            resolver.resolve(&question.qname, question.qtype)
+                                .map(|mut result| {
+                                    result.header.authoritative_answer = false;
+                                    result
+                                })
+                        },
+                        None => Ok(refused()),
                     },
-                    _ => (),
+                    DnsClass::CH if config.modes.chaos => Ok(dns_chaos::resolve(&question)),
+                    _                                  => Ok(refused()),
+                };
+
+                if let Ok(result) = result {
+                    response_packet.question_section.push(question);
+                    response_packet.header.response_code        = result.header.response_code;
+                    response_packet.header.authoritative_answer = result.header.authoritative_answer;
+
+                    // Without DO, DNSSEC records only go out when they were asked for (RFC 3225)
+                    let keep = |record: &DnsRecord| {
+                        dnssec_ok || !record.get_qtype().is_dnssec() || record.get_qtype() == qtype
+                    };
+
+                    for answer in result.answer_section.into_iter().filter(keep) {
+                        info!("Answer: {}", answer);
+                        response_packet.answer_section.push(answer);
+                    }
+
+                    for authority in result.authority_section.into_iter().filter(keep) {
+                        info!("Authority: {}", authority);
+                        response_packet.authority_section.push(authority);
+                    }
+
+                    // The upstream server's OPT describes that hop, not this one
+                    let additionals = result.additional_section
+                                            .into_iter()
+                                            .filter(keep)
+                                            .filter(|record| !matches!(record, DnsRecord::OPT { .. }));
+
+                    for additional in additionals {
+                        info!("Addition: {}", additional);
+                        response_packet.additional_section.push(additional);
+                    }
+                } else {
+                    response_packet.header.response_code = ResultCode::SERVFAIL;
                 }
+            } else {
+                response_packet.header.response_code = ResultCode::FORMERR;
+            }
+        },
+        Err(err) => {
+            warn!("Malformed Query: {}", err);
+
+            // Without at least an ID there is nobody to answer
+            if raw_request.len() < 2 {
+                return None;
             }
+
+            let packet_identifier = u16::from_be_bytes([raw_request[0], raw_request[1]]);
+
+            response_packet.header.packet_identifier = packet_identifier;
+            response_packet.header.response_code     = ResultCode::FORMERR;
         }
     }
 
-    return Err(());
+    return Some(response_packet);
+}
+
+// A NOTIFY from the primary of one of our secondary zones has it refreshed right away,
+// anyone else is refused (RFC 1996 3.10)
+fn handle_notify(config: &Config, zones: &ZoneStore, request: &DnsPacket, peer: IpAddr, key: Option<&str>) -> ResultCode {
+    let Some(question) = request.question_section.first() else {
+        return ResultCode::FORMERR;
+    };
+
+    if question.qtype != QueryType::SOA || question.qclass != DnsClass::IN {
+        return ResultCode::FORMERR;
+    }
+
+    let Some(section) = config.zones.iter().find(|zone| zone.name == question.qname) else {
+        return ResultCode::NOTAUTH;
+    };
+
+    let from_primary = section.primary.is_some_and(|primary| primary.ip().to_canonical() == peer.to_canonical());
+    if !from_primary && !is_signed_for(section, key) {
+        warn!("Refusing NOTIFY for zone {}. from {}, which isn't its primary", question.qname, peer);
+        return ResultCode::REFUSED;
+    }
+
+    info!("Received NOTIFY for zone {}. from {}", question.qname, peer);
+    zones.request_refresh(&question.qname);
+
+    return ResultCode::NOERROR;
 }
 
-fn lookup(server: (&str, u16), qname: &str, qtype: QueryType) -> Result<DnsPacket, ()> {
-    let socket = UdpSocket::bind(("0.0.0.0", 12345)).unwrap();
-    
-    let mut packet                  = dns_packet::DnsPacket::new();
-    packet.header.packet_identifier = 1234;
-    packet.header.question_count    = 1;
-    packet.header.recursion_desired = true;
-    packet.question_section
-          .push(dns_question::DnsQuestion::new(qname.to_string(), qtype));
+// Applies a dynamic update from a client that may change the zone (RFC 2136 3). The
+// question section names the zone, the answer section holds the prerequisites and
+// the authority section the updates.
+fn handle_update(config: &Config, zones: &ZoneStore, request: &DnsPacket, peer: IpAddr, key: Option<&str>) -> ResultCode {
+    let [ref zone_section] = request.question_section[..] else {
+        return ResultCode::FORMERR;
+    };
 
-    let mut request_buffer = PacketBuffer::new();
-    packet.write_packet_to_buffer(&mut request_buffer);
-    
-    socket.send_to(&request_buffer.buff[0..request_buffer.get_pos()], server).unwrap();
+    if zone_section.qtype != QueryType::SOA || zone_section.qclass != DnsClass::IN {
+        return ResultCode::FORMERR;
+    }
 
-    let mut response_buffer = PacketBuffer::new();
-    socket.recv_from(&mut response_buffer.buff).unwrap();
+    let Some(section) = config.zones.iter().find(|zone| zone.name == zone_section.qname) else {
+        return ResultCode::NOTAUTH;
+    };
 
-    return Ok(DnsPacket::get_packet_from_buffer(&mut response_buffer));
-}
\ No newline at end of file
+    // Secondaries don't pass updates on to their primary
+    if section.file.is_none() {
+        return ResultCode::NOTIMP;
+    }
+
+    if !section.allow_update.contains(&peer.to_canonical()) && !is_signed_for(section, key) {
+        warn!("Refusing update of zone {}. from {}", section.name, peer);
+        return ResultCode::REFUSED;
+    }
+
+    let result = zones.update(&section.name, |zone| {
+        update::apply(zone, &request.answer_section, &request.authority_section)
+    });
+
+    match result {
+        Ok(Some(zone)) => {
+            info!("Updated zone {}. to serial {} for {}", zone.origin, zone.serial(), peer);
+            notify::send_notify(&zone, &section.notify, config.zone_key(section), Duration::from_millis(config.resolver.timeout_ms));
+            return ResultCode::NOERROR;
+        },
+        Ok(None) => {
+            info!("Update of zone {}. from {} changed nothing", section.name, peer);
+            return ResultCode::NOERROR;
+        },
+        Err(response_code) => {
+            info!("Rejected update of zone {}. from {}: {:?}", section.name, peer, response_code);
+            return response_code;
+        },
+    }
+}
+
+fn refused() -> DnsPacket {
+    let mut result              = DnsPacket::new();
+    result.header.response_code = ResultCode::REFUSED;
+    return result;
+}
@@ -0,0 +1,651 @@
+use std::fs;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Path, PathBuf};
+
+use crate::dns_class::DnsClass;
+use crate::dns_error::DnsError;
+use crate::dns_query_type::QueryType;
+use crate::dns_record::DnsRecord;
+use crate::packet_buffer::PacketBuffer;
+
+// How deep $INCLUDE files may include further files
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+struct Token {
+    text:   String, // As written, escapes included and without the quotes
+    quoted: bool,
+}
+
+// One record or directive, which parentheses may spread over several lines
+struct Entry {
+    line:        usize,
+    blank_owner: bool, // Started with whitespace, so the owner is the previous one
+    tokens:      Vec<Token>,
+}
+
+struct Parser {
+    origin:      String,
+    default_ttl: Option<u32>, // From $TTL
+    last_owner:  Option<String>,
+    last_ttl:    Option<u32>,
+    last_class:  DnsClass,
+    records:     Vec<DnsRecord>,
+}
+
+// Reads every record of a master file (RFC 1035 5), with names relative to `origin`
+// until an $ORIGIN says otherwise. Errors name the file and line at fault.
+pub fn load(path: &Path, origin: &str) -> Result<Vec<DnsRecord>, String> {
+    let mut parser = Parser {
+        origin:      origin.to_lowercase(),
+        default_ttl: None,
+        last_owner:  None,
+        last_ttl:    None,
+        last_class:  DnsClass::IN,
+        records:     Vec::new(),
+    };
+
+    parser.parse_file(path, 0)?;
+    return Ok(parser.records);
+}
+
+impl Parser {
+    fn parse_file(&mut self, path: &Path, depth: usize) -> Result<(), String> {
+        let text = fs::read_to_string(path)
+                      .map_err(|err| format!("could not read {}: {}", path.display(), err))?;
+
+        let entries = tokenize(&text).map_err(|err| format!("{}:{}", path.display(), err))?;
+        for entry in entries {
+            self.parse_entry(path, &entry, depth)
+                .map_err(|err| format!("{}:{}: {}", path.display(), entry.line, err))?;
+        }
+
+        return Ok(());
+    }
+
+    fn parse_entry(&mut self, path: &Path, entry: &Entry, depth: usize) -> Result<(), String> {
+        let tokens = &entry.tokens;
+        let args   = &tokens[1..];
+
+        match tokens[0].text.to_uppercase().as_str() {
+            "$ORIGIN" if !entry.blank_owner => {
+                expect_args(args, 1)?;
+                self.origin = self.name(&args[0])?;
+            },
+            "$TTL" if !entry.blank_owner => {
+                expect_args(args, 1)?;
+                self.default_ttl = Some(parse_ttl(&args[0].text)?);
+            },
+            "$INCLUDE" if !entry.blank_owner => {
+                if args.is_empty() || args.len() > 2 {
+                    return Err("$INCLUDE expects a file name and optionally an origin".to_string());
+                }
+
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err("$INCLUDE nested too deeply".to_string());
+                }
+
+                // Relative to the including file, and the origin only changes for the
+                // included file (RFC 1035 5.1)
+                let mut included = PathBuf::from(String::from_utf8_lossy(&unescape(&args[0].text)?).to_string());
+                if included.is_relative() {
+                    included = path.parent().unwrap_or(Path::new("")).join(included);
+                }
+
+                let saved_origin = self.origin.clone();
+                if let Some(origin) = args.get(1) {
+                    self.origin = self.name(origin)?;
+                }
+
+                let result  = self.parse_file(&included, depth + 1);
+                self.origin = saved_origin;
+                result?;
+            },
+            directive if directive.starts_with('$') && !entry.blank_owner => {
+                return Err(format!("unknown directive {}", tokens[0].text));
+            },
+            _ => {
+                let record = self.parse_record(entry)?;
+                self.records.push(record);
+            },
+        }
+
+        return Ok(());
+    }
+
+    // [<owner>] [<ttl>] [<class>] <type> <rdata>, with TTL and class in either order
+    fn parse_record(&mut self, entry: &Entry) -> Result<DnsRecord, String> {
+        let mut tokens = entry.tokens.iter();
+
+        let owner = match entry.blank_owner {
+            true  => self.last_owner.clone().ok_or("record without an owner name")?,
+            false => self.name(tokens.next().unwrap())?,
+        };
+
+        let mut ttl   = None;
+        let mut class = None;
+        let qtype     = loop {
+            let token = tokens.next().ok_or("record without a type")?;
+            if ttl.is_none() && token.text.starts_with(|c: char| c.is_ascii_digit()) {
+                ttl = Some(parse_ttl(&token.text)?);
+            } else if let (None, Some(parsed)) = (class, parse_class(&token.text)) {
+                class = Some(parsed);
+            } else {
+                break parse_type(&token.text)?;
+            }
+        };
+
+        let class = class.unwrap_or(self.last_class);
+        let rdata = tokens.collect::<Vec<_>>();
+
+        let record = match rdata.first() {
+            Some(token) if token.text == "\\#" && !token.quoted => self.parse_generic(&owner, qtype, class, &rdata[1..])?,
+            _                                                   => self.parse_rdata(&owner, qtype, class, &rdata)?,
+        };
+
+        // Without $TTL the last TTL given applies, and an SOA can fall back to its minimum
+        let ttl = match (ttl.or(self.default_ttl).or(self.last_ttl), &record) {
+            (Some(ttl), _)                         => ttl,
+            (None, DnsRecord::SOA { minimum, .. }) => *minimum,
+            (None, _)                              => return Err("no TTL given and no $TTL in effect".to_string()),
+        };
+
+        let mut record = record;
+        record.set_ttl(ttl);
+
+        self.last_owner = Some(owner);
+        self.last_ttl   = Some(ttl);
+        self.last_class = class;
+
+        return Ok(record);
+    }
+
+    fn parse_rdata(&self, owner: &str, qtype: QueryType, class: DnsClass, rdata: &[&Token]) -> Result<DnsRecord, String> {
+        let domain = owner.to_string();
+
+        let record = match qtype {
+            QueryType::A => {
+                expect_rdata(rdata, 1)?;
+                DnsRecord::A {
+                    domain: domain,
+                    addr:   parse_number::<Ipv4Addr>(rdata[0], "IPv4 address")?,
+                    class:  class,
+                    ttl:    0,
+                }
+            },
+            QueryType::NS => {
+                expect_rdata(rdata, 1)?;
+                DnsRecord::NS { domain: domain, host: self.name(rdata[0])?, class: class, ttl: 0 }
+            },
+            QueryType::CNAME => {
+                expect_rdata(rdata, 1)?;
+                DnsRecord::CNAME { domain: domain, host: self.name(rdata[0])?, class: class, ttl: 0 }
+            },
+            QueryType::PTR => {
+                expect_rdata(rdata, 1)?;
+                DnsRecord::PTR { domain: domain, host: self.name(rdata[0])?, class: class, ttl: 0 }
+            },
+            QueryType::SOA => {
+                expect_rdata(rdata, 7)?;
+                DnsRecord::SOA {
+                    domain:  domain,
+                    mname:   self.name(rdata[0])?,
+                    rname:   self.name(rdata[1])?,
+                    serial:  parse_number(rdata[2], "serial")?,
+                    refresh: parse_ttl(&rdata[3].text)?,
+                    retry:   parse_ttl(&rdata[4].text)?,
+                    expire:  parse_ttl(&rdata[5].text)?,
+                    minimum: parse_ttl(&rdata[6].text)?,
+                    class:   class,
+                    ttl:     0,
+                }
+            },
+            QueryType::MX => {
+                expect_rdata(rdata, 2)?;
+                DnsRecord::MX {
+                    domain:   domain,
+                    priority: parse_number(rdata[0], "preference")?,
+                    host:     self.name(rdata[1])?,
+                    class:    class,
+                    ttl:      0,
+                }
+            },
+            QueryType::TXT => {
+                if rdata.is_empty() {
+                    return Err("TXT expects at least one string".to_string());
+                }
+
+                let mut data = Vec::new();
+                for token in rdata {
+                    let string = unescape(&token.text)?;
+                    if string.len() > 255 {
+                        return Err("TXT string longer than 255 bytes".to_string());
+                    }
+
+                    data.push(string);
+                }
+
+                DnsRecord::TXT { domain: domain, data: data, class: class, ttl: 0 }
+            },
+            QueryType::AAAA => {
+                expect_rdata(rdata, 1)?;
+                DnsRecord::AAAA {
+                    domain: domain,
+                    addr:   parse_number::<Ipv6Addr>(rdata[0], "IPv6 address")?,
+                    class:  class,
+                    ttl:    0,
+                }
+            },
+            QueryType::SRV => {
+                expect_rdata(rdata, 4)?;
+                DnsRecord::SRV {
+                    domain:   domain,
+                    priority: parse_number(rdata[0], "priority")?,
+                    weight:   parse_number(rdata[1], "weight")?,
+                    port:     parse_number(rdata[2], "port")?,
+                    host:     self.name(rdata[3])?,
+                    class:    class,
+                    ttl:      0,
+                }
+            },
+            _ => return Err(format!("{} records need the generic \\# form", qtype)),
+        };
+
+        return Ok(record);
+    }
+
+    // \# <length> <hex>... (RFC 3597 5), decoded like the same RDATA off the wire so
+    // known types still come out as their own variant
+    fn parse_generic(&self, owner: &str, qtype: QueryType, class: DnsClass, rdata: &[&Token]) -> Result<DnsRecord, String> {
+        let (length, hex) = rdata.split_first().ok_or("\\# expects a length")?;
+        let length: usize = parse_number(length, "length")?;
+
+        let hex: String = hex.iter().map(|token| token.text.as_str()).collect();
+        if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            return Err(format!("invalid hex RDATA {:?}", hex));
+        }
+
+        let data: Vec<u8> = (0..hex.len()).step_by(2)
+                                           .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+                                           .collect();
+
+        if data.len() != length || length > u16::MAX as usize {
+            return Err(format!("\\# length {} does not match the {} bytes of RDATA", length, data.len()));
+        }
+
+        if qtype == QueryType::OPT {
+            return Err("OPT records can't appear in a zone".to_string());
+        }
+
+        let mut buffer = PacketBuffer::with_limit(usize::MAX);
+        let write      = |buffer: &mut PacketBuffer| -> Result<(), DnsError> {
+            buffer.write_qname(owner)?;
+            buffer.write_u16(qtype.to_num())?;
+            buffer.write_u16(class.to_num())?;
+            buffer.write_u32(0)?;
+            buffer.write_u16(data.len() as u16)?;
+            buffer.write_bytes(&data)?;
+            return Ok(());
+        };
+
+        write(&mut buffer).map_err(|err| err.to_string())?;
+        buffer.set_pos(0);
+
+        return DnsRecord::read(&mut buffer).map_err(|err| format!("invalid {} RDATA: {}", qtype, err));
+    }
+
+    // "@" is the origin, names without a trailing dot are relative to it
+    fn name(&self, token: &Token) -> Result<String, String> {
+        if token.text == "@" && !token.quoted {
+            return Ok(self.origin.clone());
+        }
+
+        let name = String::from_utf8(unescape(&token.text)?)
+                          .map_err(|_| format!("invalid name {:?}", token.text))?
+                          .to_lowercase();
+
+        let name = match name.strip_suffix('.') {
+            Some(absolute)                 => absolute.to_string(),
+            None if self.origin.is_empty() => name,
+            None                           => format!("{}.{}", name, self.origin),
+        };
+
+        if name.len() > 253 || name.split('.').any(|label| label.len() > 63 || (label.is_empty() && !name.is_empty())) {
+            return Err(format!("invalid name {:?}", token.text));
+        }
+
+        return Ok(name);
+    }
+}
+
+// Splits the file into entries, dropping comments and joining lines inside parentheses
+fn tokenize(text: &str) -> Result<Vec<Entry>, String> {
+    let mut entries = Vec::new();
+    let mut tokens  = Vec::new();
+    let mut token   = String::new();
+
+    let mut line        = 1;
+    let mut entry_line  = 1;
+    let mut depth       = 0;
+    let mut blank_owner = false;
+    let mut line_start  = true;
+
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if line_start && depth == 0 && tokens.is_empty() {
+            blank_owner = c == ' ' || c == '\t';
+            entry_line  = line;
+        }
+        line_start = false;
+
+        match c {
+            ' ' | '\t' | '\r' | '\n' | '(' | ')' | ';' => {
+                if !token.is_empty() {
+                    tokens.push(Token { text: std::mem::take(&mut token), quoted: false });
+                }
+
+                match c {
+                    '(' => depth += 1,
+                    ')' => {
+                        if depth == 0 {
+                            return Err(format!("{}: unbalanced )", line));
+                        }
+                        depth -= 1;
+                    },
+                    ';' => {
+                        while chars.next_if(|&next| next != '\n').is_some() {}
+                    },
+                    '\n' => {
+                        line      += 1;
+                        line_start = true;
+                        if depth == 0 && !tokens.is_empty() {
+                            entries.push(Entry {
+                                line:        entry_line,
+                                blank_owner: blank_owner,
+                                tokens:      std::mem::take(&mut tokens),
+                            });
+                        }
+                    },
+                    _ => (),
+                }
+            },
+            '"' => {
+                if !token.is_empty() {
+                    return Err(format!("{}: unexpected quote in {:?}", line, token));
+                }
+
+                let mut quoted = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"')  => break,
+                        Some('\\') => {
+                            quoted.push('\\');
+                            quoted.extend(chars.next());
+                        },
+                        Some('\n') | None => return Err(format!("{}: unterminated string", line)),
+                        Some(c)    => quoted.push(c),
+                    }
+                }
+
+                tokens.push(Token { text: quoted, quoted: true });
+            },
+            '\\' => {
+                // Keeps the escape for unescape(), so \; and \( are not special here
+                token.push('\\');
+                token.extend(chars.next());
+            },
+            _ => token.push(c),
+        }
+    }
+
+    if depth > 0 {
+        return Err(format!("{}: unbalanced (", line));
+    }
+
+    if !token.is_empty() {
+        tokens.push(Token { text: token, quoted: false });
+    }
+
+    if !tokens.is_empty() {
+        entries.push(Entry { line: entry_line, blank_owner: blank_owner, tokens: tokens });
+    }
+
+    return Ok(entries);
+}
+
+// Resolves \X to X and \DDD to the byte with that decimal value (RFC 1035 5.1)
+fn unescape(text: &str) -> Result<Vec<u8>, String> {
+    let bytes      = text.as_bytes();
+    let mut result = Vec::new();
+    let mut i      = 0;
+
+    while i < bytes.len() {
+        if bytes[i] != b'\\' {
+            result.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        let digits = &bytes[i + 1..bytes.len().min(i + 4)];
+        if digits.len() == 3 && digits.iter().all(u8::is_ascii_digit) {
+            let value = digits.iter().fold(0u32, |value, digit| value * 10 + (digit - b'0') as u32);
+            if value > 255 {
+                return Err(format!("invalid escape in {:?}", text));
+            }
+
+            result.push(value as u8);
+            i += 4;
+        } else if let Some(&escaped) = bytes.get(i + 1) {
+            result.push(escaped);
+            i += 2;
+        } else {
+            return Err(format!("dangling escape in {:?}", text));
+        }
+    }
+
+    return Ok(result);
+}
+
+// Seconds, or BIND style units like 1h30m or 2w
+fn parse_ttl(text: &str) -> Result<u32, String> {
+    let invalid = || format!("invalid TTL {:?}", text);
+
+    if let Ok(seconds) = text.parse() {
+        return Ok(seconds);
+    }
+
+    let mut total: u64 = 0;
+    let mut number     = String::new();
+    for c in text.chars() {
+        if c.is_ascii_digit() {
+            number.push(c);
+            continue;
+        }
+
+        let unit = match c.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _   => return Err(invalid()),
+        };
+
+        let value: u64 = number.parse().map_err(|_| invalid())?;
+        total         += value * unit;
+        number.clear();
+    }
+
+    if !number.is_empty() || total > u32::MAX as u64 {
+        return Err(invalid());
+    }
+
+    return Ok(total as u32);
+}
+
+fn parse_class(text: &str) -> Option<DnsClass> {
+    let text = text.to_uppercase();
+    match text.as_str() {
+        "IN" => Some(DnsClass::IN),
+        "CH" => Some(DnsClass::CH),
+        "HS" => Some(DnsClass::HS),
+        _    => text.strip_prefix("CLASS")
+                    .and_then(|num| num.parse().ok())
+                    .map(DnsClass::from_num),
+    }
+}
+
+fn parse_type(text: &str) -> Result<QueryType, String> {
+    let text = text.to_uppercase();
+    let qtype = match text.as_str() {
+        "A"     => QueryType::A,
+        "NS"    => QueryType::NS,
+        "CNAME" => QueryType::CNAME,
+        "SOA"   => QueryType::SOA,
+        "PTR"   => QueryType::PTR,
+        "MX"    => QueryType::MX,
+        "TXT"   => QueryType::TXT,
+        "AAAA"  => QueryType::AAAA,
+        "SRV"   => QueryType::SRV,
+        _       => {
+            let num = text.strip_prefix("TYPE")
+                          .and_then(|num| num.parse().ok())
+                          .ok_or_else(|| format!("unknown record type {}", text))?;
+            QueryType::from_num(num)
+        }
+    };
+
+    return Ok(qtype);
+}
+
+fn parse_number<T: std::str::FromStr>(token: &Token, what: &str) -> Result<T, String> {
+    return token.text.parse().map_err(|_| format!("invalid {} {:?}", what, token.text));
+}
+
+fn expect_args(args: &[Token], count: usize) -> Result<(), String> {
+    if args.len() != count {
+        return Err(format!("expected {} argument(s), found {}", count, args.len()));
+    }
+
+    return Ok(());
+}
+
+fn expect_rdata(rdata: &[&Token], count: usize) -> Result<(), String> {
+    if rdata.len() != count {
+        return Err(format!("expected {} RDATA field(s), found {}", count, rdata.len()));
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes the files to a directory of their own and loads the first one, giving
+    // back the records in presentation format
+    fn load_files(test: &str, origin: &str, files: &[(&str, &str)]) -> Result<Vec<String>, String> {
+        let dir = std::env::temp_dir().join(format!("master_file_{}_{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        for (name, text) in files {
+            fs::write(dir.join(name), text).unwrap();
+        }
+
+        let result = load(&dir.join(files[0].0), origin);
+        fs::remove_dir_all(&dir).unwrap();
+
+        return result.map(|records| records.iter().map(|record| record.to_string()).collect());
+    }
+
+    #[test]
+    fn origin_and_ttl_directives() {
+        let text = "$TTL 1h\n\
+                    @ SOA ns hostmaster 1 3600 600 86400 60\n\
+                    www A 192.0.2.1\n\
+                    $ORIGIN sub.example.com.\n\
+                    host 60 IN A 192.0.2.2\n\
+                    abs.example.com. AAAA 2001:db8::1\n";
+
+        let records = load_files("origin", "example.com", &[("zone", text)]).unwrap();
+        assert_eq!(records, [
+            "example.com. 3600 IN SOA ns.example.com. hostmaster.example.com. 1 3600 600 86400 60",
+            "www.example.com. 3600 IN A 192.0.2.1",
+            "host.sub.example.com. 60 IN A 192.0.2.2",
+            "abs.example.com. 3600 IN AAAA 2001:db8::1",
+        ]);
+    }
+
+    #[test]
+    fn ttl_falls_back_to_the_last_one_given() {
+        let text = "@ 300 SOA ns hostmaster 1 3600 600 86400 60\n\
+                    www A 192.0.2.1\n\
+                    \x20   120 A 192.0.2.2\n";
+
+        let records = load_files("last_ttl", "example.com", &[("zone", text)]).unwrap();
+        assert_eq!(records[1], "www.example.com. 300 IN A 192.0.2.1");
+        assert_eq!(records[2], "www.example.com. 120 IN A 192.0.2.2");
+    }
+
+    #[test]
+    fn include_with_its_own_origin() {
+        let main     = "$TTL 300\n$INCLUDE inc sub\nwww A 192.0.2.1\n";
+        let included = "host A 192.0.2.2\n@ TXT \"in sub\"\n";
+
+        let records = load_files("include", "example.com", &[("zone", main), ("inc", included)]).unwrap();
+        assert_eq!(records, [
+            "host.sub.example.com. 300 IN A 192.0.2.2",
+            "sub.example.com. 300 IN TXT \"in sub\"",
+            "www.example.com. 300 IN A 192.0.2.1",
+        ]);
+    }
+
+    #[test]
+    fn parentheses_span_lines() {
+        let text = "$TTL 300\n\
+                    @ SOA ns hostmaster ( 2024 ; serial\n\
+                    \x20   1h 10m  ; refresh, retry\n\
+                    \x20   1w 60 )\n\
+                    next A 192.0.2.1 ; comment (\n";
+
+        let records = load_files("parentheses", "example.com", &[("zone", text)]).unwrap();
+        assert_eq!(records, [
+            "example.com. 300 IN SOA ns.example.com. hostmaster.example.com. 2024 3600 600 604800 60",
+            "next.example.com. 300 IN A 192.0.2.1",
+        ]);
+    }
+
+    #[test]
+    fn generic_rdata() {
+        let text = "$TTL 300\n\
+                    a TYPE1 \\# 4 C0000201\n\
+                    b TYPE65534 \\# 4 dead beef\n\
+                    c TYPE65535 \\# 0\n";
+
+        let records = load_files("generic", "example.com", &[("zone", text)]).unwrap();
+        assert_eq!(records, [
+            "a.example.com. 300 IN A 192.0.2.1",
+            "b.example.com. 300 IN TYPE65534 \\# 4 deadbeef",
+            "c.example.com. 300 IN TYPE65535 \\# 0",
+        ]);
+
+        let wrong_length = "$TTL 300\na TYPE65534 \\# 3 deadbeef\n";
+        assert!(load_files("generic_length", "example.com", &[("zone", wrong_length)]).is_err());
+    }
+
+    #[test]
+    fn errors_name_the_file_and_line() {
+        let text = "$TTL 300\n\
+                    www A 192.0.2.1\n\
+                    \n\
+                    bad A 192.0.2.300\n";
+
+        let err = load_files("error", "example.com", &[("zone", text)]).unwrap_err();
+        assert!(err.contains("zone:4: invalid IPv4 address"), "{}", err);
+
+        let main     = "$TTL 300\n$INCLUDE inc\n";
+        let included = "ok A 192.0.2.1\nbad MX 10\n";
+
+        let err = load_files("include_error", "example.com", &[("zone", main), ("inc", included)]).unwrap_err();
+        assert!(err.contains("inc:2: expected 2 RDATA field(s), found 1"), "{}", err);
+    }
+}
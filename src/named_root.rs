@@ -1,50 +1,71 @@
 use std::fs;
-use rand::Rng;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
 
 pub struct NamedRoot {
     pub domain: String,
-    pub ipv4:   String,
-    pub ipv6:   String,
+    pub ipv4:   Option<Ipv4Addr>,
+    pub ipv6:   Option<Ipv6Addr>,
 }
 
 impl NamedRoot {
-    fn new(domain: &str, ipv4: &str, ipv6: &str) -> Self {
+    fn new(domain: &str) -> Self {
         let mut domain = domain.to_string();
         domain.pop();
 
         Self {
             domain: domain,
-            ipv4:   ipv4.to_string(),
-            ipv6:   ipv6.to_string(),
+            ipv4:   None,
+            ipv6:   None,
         }
     }
-    
-    pub fn get_named_root() -> Self {
-        let file             = fs::read_to_string("assets/named.root.txt").unwrap();
-        let lines: Vec<&str> = file.split("\n").collect();
-
-        let named_root_to_select   = rand::thread_rng().gen_range(0..13);
-        let mut current_named_root = 0;
-
-        let mut i = 0;
-        while i < lines.len() {
-            let line = lines[i];
-            let chr  = line.as_bytes()[0];
-            if chr == ('.' as u8) {
-                if named_root_to_select == current_named_root {
-                    let ipv4: Vec<&str> = lines[i+1].split_whitespace().collect();
-                    let ipv6: Vec<&str> = lines[i+2].split_whitespace().collect();
-                    
-                    return NamedRoot::new(ipv4[0], ipv4[3], ipv6[3]);
-                }
-
-                current_named_root += 1;
-                i += 2;
+
+    // Every root server listed in the hints file, in file order. The file is a master
+    // file with the NS records for "." followed by each server's A and AAAA records.
+    pub fn get_named_roots(path: &Path) -> io::Result<Vec<Self>> {
+        let file            = fs::read_to_string(path)?;
+        let mut named_roots = Vec::new();
+
+        for line in file.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() < 4 || fields[0].starts_with(';') {
+                continue;
+            }
+
+            let (domain, qtype, data) = (fields[0], fields[2], fields[3]);
+            if qtype == "NS" && domain == "." {
+                named_roots.push(NamedRoot::new(data));
+                continue;
+            }
+
+            let domain = domain.trim_end_matches('.');
+            let named_root = named_roots.iter_mut().find(|root| root.domain.eq_ignore_ascii_case(domain));
+            let Some(named_root) = named_root else {
+                continue;
+            };
+
+            match qtype {
+                "A"    => named_root.ipv4 = data.parse().ok(),
+                "AAAA" => named_root.ipv6 = data.parse().ok(),
+                _      => (),
             }
+        }
+
+        return Ok(named_roots);
+    }
+
+    pub fn get_addrs(&self) -> Vec<IpAddr> {
+        let mut addrs = Vec::new();
 
-            i += 1;
+        if let Some(ipv4) = self.ipv4 {
+            addrs.push(IpAddr::V4(ipv4));
         }
 
-        return NamedRoot::new("", "", "");
+        if let Some(ipv6) = self.ipv6 {
+            addrs.push(IpAddr::V6(ipv6));
+        }
+
+        return addrs;
     }
-}
\ No newline at end of file
+}
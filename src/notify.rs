@@ -0,0 +1,110 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::dns_class::DnsClass;
+use crate::dns_packet::DnsPacket;
+use crate::dns_query_type::QueryType;
+use crate::dns_question::DnsQuestion;
+use crate::dns_result_code::ResultCode;
+use crate::packet_buffer::{PacketBuffer, MAX_PACKET_SIZE, UDP_PACKET_SIZE};
+use crate::tsig::{Key, Session};
+use crate::zone::Zone;
+
+pub const OPCODE_NOTIFY: u8 = 4;
+
+// Unanswered NOTIFYs are repeated this many times in all, waiting twice as long as
+// the time before for each
+const NOTIFY_ATTEMPTS: u32 = 5;
+
+// Tells the secondaries the zone changed so they refresh without waiting for their
+// timers (RFC 1996). Each one is notified from its own thread, since retries can take
+// a while. With a key the NOTIFY is signed, and so has to be the reply.
+pub fn send_notify(zone: &Zone, secondaries: &[SocketAddr], key: Option<&Key>, timeout: Duration) {
+    for &secondary in secondaries {
+        let mut request                     = DnsPacket::new();
+        request.header.packet_identifier    = rand::random();
+        request.header.operation_code       = OPCODE_NOTIFY;
+        request.header.authoritative_answer = true;
+        request.question_section.push(DnsQuestion::new(zone.origin.clone(), QueryType::SOA, DnsClass::IN));
+        request.answer_section.push(zone.soa().clone());
+
+        let serial = zone.serial();
+        let key    = key.cloned();
+        thread::spawn(move || notify(secondary, request, serial, key, timeout));
+    }
+}
+
+fn notify(secondary: SocketAddr, mut request: DnsPacket, serial: u32, key: Option<Key>, timeout: Duration) {
+    let origin = request.question_section[0].qname.clone();
+
+    let mut wait = timeout;
+    for attempt in 1..=NOTIFY_ATTEMPTS {
+        match exchange(secondary, &mut request, key.as_ref(), wait) {
+            Ok(ResultCode::NOERROR) => {
+                debug!("{} acknowledged the NOTIFY for zone {}. serial {}", secondary, origin, serial);
+                return;
+            },
+            Ok(response_code) => {
+                warn!("{} answered the NOTIFY for zone {}. serial {} with {:?}", secondary, origin, serial, response_code);
+                return;
+            },
+            Err(()) => debug!("No reply from {} to the NOTIFY for zone {}., attempt {}", secondary, origin, attempt),
+        }
+
+        wait *= 2;
+    }
+
+    warn!("{} never acknowledged the NOTIFY for zone {}. serial {}", secondary, origin, serial);
+}
+
+fn exchange(secondary: SocketAddr, request: &mut DnsPacket, key: Option<&Key>, timeout: Duration) -> Result<ResultCode, ()> {
+    let local_addr = match secondary.ip() {
+        IpAddr::V4(_) => "0.0.0.0",
+        IpAddr::V6(_) => "::",
+    };
+
+    let socket = UdpSocket::bind((local_addr, 0)).map_err(|_| ())?;
+
+    let mut request_buffer = PacketBuffer::with_limit(UDP_PACKET_SIZE);
+    request.write_packet_to_buffer(&mut request_buffer).map_err(|_| ())?;
+
+    let mut session = key.map(Session::new);
+    let message     = match session {
+        Some(ref mut session) => session.sign(request_buffer.get_data()),
+        None                  => request_buffer.get_data().to_vec(),
+    };
+
+    socket.send_to(&message, secondary).map_err(|_| ())?;
+
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(());
+        }
+
+        socket.set_read_timeout(Some(remaining)).map_err(|_| ())?;
+
+        let mut data   = [0; MAX_PACKET_SIZE];
+        let (len, src) = socket.recv_from(&mut data).map_err(|_| ())?;
+        if src != secondary {
+            continue;
+        }
+
+        let mut response_buffer = PacketBuffer::from_bytes(&data[..len]);
+        let response = match DnsPacket::get_packet_from_buffer(&mut response_buffer) {
+            Ok(response) if response.is_response_to(request) => response,
+            _                                                => continue,
+        };
+
+        if let Some(ref mut session) = session {
+            if let Err(err) = session.verify(&data[..len], &response) {
+                warn!("Discarding reply from {} to the NOTIFY for zone {}.: {}", secondary, request.question_section[0].qname, err);
+                continue;
+            }
+        }
+
+        return Ok(response.header.response_code);
+    }
+}
@@ -1,13 +1,44 @@
+use std::collections::HashMap;
+
+use crate::dns_error::DnsError;
+
+// Largest message DNS can carry, bounded by the 2 byte length prefix used over TCP
+pub const MAX_PACKET_SIZE: usize = 65535;
+
+// Largest message allowed over plain UDP without EDNS (RFC 1035 4.2.1)
+pub const UDP_PACKET_SIZE: usize = 512;
+
+// UDP payload size we advertise and accept with EDNS (RFC 6891 6.2.5)
+pub const EDNS_PACKET_SIZE: usize = 4096;
+
+// Compression pointers only have 14 bits for the offset
+const MAX_POINTER_OFFSET: usize = 0x3FFF;
+
 pub struct PacketBuffer {
-    pub buff: [u8; 512],
-    pos: usize,
+    buff:  Vec<u8>,
+    pos:   usize,
+    limit: usize,
+    names: HashMap<String, u16>, // Offset of every name suffix written so far
 }
 
 impl PacketBuffer {
-    pub fn new() -> Self {
+    // Buffer for building a message that has to fit in `limit` bytes
+    pub fn with_limit(limit: usize) -> Self {
+        Self {
+            buff:  Vec::new(),
+            pos:   0,
+            limit: limit.min(MAX_PACKET_SIZE),
+            names: HashMap::new(),
+        }
+    }
+
+    // Buffer for reading a received message
+    pub fn from_bytes(data: &[u8]) -> Self {
         Self {
-            buff: [0; 512],
-            pos: 0,
+            buff:  data.to_vec(),
+            pos:   0,
+            limit: MAX_PACKET_SIZE,
+            names: HashMap::new(),
         }
     }
 
@@ -15,115 +46,172 @@ impl PacketBuffer {
         return self.pos;
     }
 
-    pub fn get_range(&mut self, start: usize, len: usize) -> &[u8] {
-        if start + len >= 512 {
-            return &self.buff[start..start];
+    pub fn get_range(&self, start: usize, len: usize) -> Result<&[u8], DnsError> {
+        if start + len > self.buff.len() {
+            return Err(DnsError::EndOfBuffer);
         }
 
-        return &self.buff[start..start + len as usize];
+        return Ok(&self.buff[start..start + len]);
+    }
+
+    // Everything written so far
+    pub fn get_data(&self) -> &[u8] {
+        return &self.buff;
     }
 
-    fn set(&mut self, pos: usize, val: u8) {
-        if pos < 512 {
-            self.buff[pos] = val;
+    fn get(&self, pos: usize) -> Result<u8, DnsError> {
+        if pos >= self.buff.len() {
+            return Err(DnsError::EndOfBuffer);
         }
+
+        return Ok(self.buff[pos]);
     }
 
-    pub fn set_u16(&mut self, pos: usize, val: u16) {
-        self.set(pos, (val >> 8) as u8);
-        self.set(pos + 1, (val & 0xFF) as u8);
+    fn set(&mut self, pos: usize, val: u8) -> Result<(), DnsError> {
+        if pos >= self.buff.len() {
+            return Err(DnsError::EndOfBuffer);
+        }
+
+        self.buff[pos] = val;
+        return Ok(());
+    }
+
+    pub fn set_u16(&mut self, pos: usize, val: u16) -> Result<(), DnsError> {
+        self.set(pos, (val >> 8) as u8)?;
+        self.set(pos + 1, (val & 0xFF) as u8)?;
+
+        return Ok(());
     }
 
     pub fn set_pos(&mut self, new_pos: usize) {
         self.pos = new_pos;
     }
 
-    pub fn step_pos(&mut self, step_pos: usize) {
+    pub fn step_pos(&mut self, step_pos: usize) -> Result<(), DnsError> {
+        if self.pos + step_pos > self.buff.len() {
+            return Err(DnsError::EndOfBuffer);
+        }
+
         self.pos += step_pos;
+        return Ok(());
     }
 
-    fn write(&mut self, data: u8) {
-        self.buff[self.pos] = data;
+    fn write(&mut self, data: u8) -> Result<(), DnsError> {
+        if self.pos >= self.limit {
+            return Err(DnsError::BufferFull);
+        }
 
-        if self.pos < 512 {
-            self.pos += 1;
+        if self.pos < self.buff.len() {
+            self.buff[self.pos] = data;
+        } else {
+            self.buff.push(data);
         }
+
+        self.pos += 1;
+        return Ok(());
     }
 
-    pub fn write_u8(&mut self, data: u8) {
-        self.write(data);
+    pub fn write_u8(&mut self, data: u8) -> Result<(), DnsError> {
+        return self.write(data);
     }
 
-    pub fn write_u16(&mut self, data: u16) {
-        self.write((data >> 8) as u8);
-        self.write((data & 0xFF) as u8);
+    pub fn write_u16(&mut self, data: u16) -> Result<(), DnsError> {
+        self.write((data >> 8) as u8)?;
+        self.write((data & 0xFF) as u8)?;
+
+        return Ok(());
     }
 
-    pub fn write_u32(&mut self, data: u32) {
-        self.write(((data >> 24) & 0xFF) as u8);
-        self.write(((data >> 16) & 0xFF) as u8);
-        self.write(((data >> 8) & 0xFF) as u8);
-        self.write(((data >> 0) & 0xFF) as u8);
+    pub fn write_u32(&mut self, data: u32) -> Result<(), DnsError> {
+        self.write(((data >> 24) & 0xFF) as u8)?;
+        self.write(((data >> 16) & 0xFF) as u8)?;
+        self.write(((data >> 8) & 0xFF) as u8)?;
+        self.write((data & 0xFF) as u8)?;
+
+        return Ok(());
     }
 
-    pub fn read(&mut self) -> u8 {
-        if self.pos >= 512 {
-            return self.buff[self.pos];
+    pub fn write_bytes(&mut self, data: &[u8]) -> Result<(), DnsError> {
+        for byte in data {
+            self.write(*byte)?;
         }
 
-        let buff = self.buff[self.pos];
+        return Ok(());
+    }
+
+    pub fn read(&mut self) -> Result<u8, DnsError> {
+        let buff  = self.get(self.pos)?;
         self.pos += 1;
 
-        return buff;
+        return Ok(buff);
     }
 
-    pub fn read_u16(&mut self) -> u16 {
-        let result = (self.read() as u16) << 8 | (self.read() as u16);
-        return result;
+    pub fn read_u16(&mut self) -> Result<u16, DnsError> {
+        let result = (self.read()? as u16) << 8 | (self.read()? as u16);
+        return Ok(result);
     }
 
-    pub fn read_u32(&mut self) -> u32 {
-        let result = (self.read_u16() as u32) << 16 | (self.read_u16() as u32);
-        return result;
+    pub fn read_u32(&mut self) -> Result<u32, DnsError> {
+        let result = (self.read_u16()? as u32) << 16 | (self.read_u16()? as u32);
+        return Ok(result);
     }
 
-    pub fn get_qname(&mut self) -> String {
+    pub fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>, DnsError> {
+        let result = self.get_range(self.pos, len)?.to_vec();
+        self.pos  += len;
+
+        return Ok(result);
+    }
+
+    // Reads a name, following compression pointers. Each pointer has to point before
+    // the labels read so far, which rules out loops however many pointers there are.
+    pub fn get_qname(&mut self) -> Result<String, DnsError> {
         let mut qname = String::new();
         let mut pos   = self.get_pos();
+        let mut start = pos; // Where the labels being read started
 
-        let mut jumped          = false;
-        let max_jumps           = 5;
-        let mut jumps_performed = 0;
+        let mut jumped = false;
+
+        // Wire length of the name, including length octets and the root label
+        let mut name_len = 1;
 
         let mut delim = "";
         loop {
-            if jumps_performed > max_jumps {
-                break;
-            }
-
-            let len = self.buff[pos];
+            let len = self.get(pos)?;
             if (len & 0xC0) == 0xC0 {
+                let b2 = self.get(pos + 1)? as u16;
                 if !jumped {
                     self.set_pos(pos + 2);
                 }
 
-                let b2     = self.buff[pos + 1] as u16;
-                let offset = (((len as u16) ^ 0xC0) << 8) | b2;
-                pos        = offset as usize;
+                let offset = ((((len as u16) ^ 0xC0) << 8) | b2) as usize;
+                if offset >= start {
+                    return Err(DnsError::PointerLoop);
+                }
 
-                jumped           = true;
-                jumps_performed += 1;
+                pos    = offset;
+                start  = offset;
+                jumped = true;
 
                 continue;
             }
+            else if len > 63 {
+                // 0x40 and 0x80 prefixes are reserved, treat them like an oversized label
+                return Err(DnsError::LabelTooLong);
+            }
             else {
                 pos += 1;
                 if len == 0 {
                     break;
                 }
 
+                name_len += len as usize + 1;
+                if name_len > 255 {
+                    return Err(DnsError::LabelTooLong);
+                }
+
                 qname.push_str(delim);
-                let str_buffer = self.get_range(pos, len as usize);
+                let str_buffer = self.get_range(pos, len as usize)?;
                 qname.push_str(&String::from_utf8_lossy(str_buffer).to_lowercase());
 
                 delim = ".";
@@ -135,18 +223,123 @@ impl PacketBuffer {
             self.set_pos(pos);
         }
 
-        return qname;
+        return Ok(qname);
     }
 
-    pub fn write_qname(&mut self, qname: &str) {
-        for label in qname.split('.') {
-            self.write_u8(label.len() as u8);
+    // Writes the name, replacing the longest suffix that was already written in this
+    // packet with a pointer to it (RFC 1035 4.1.4)
+    pub fn write_qname(&mut self, qname: &str) -> Result<(), DnsError> {
+        // The root name is just the terminating zero
+        let labels: Vec<&str> = qname.split('.').filter(|label| !label.is_empty()).collect();
+
+        for i in 0..labels.len() {
+            let suffix = labels[i..].join(".").to_lowercase();
+            if let Some(&offset) = self.names.get(&suffix) {
+                return self.write_u16(0xC000 | offset);
+            }
 
-            for byte in label.as_bytes() {
-                self.write_u8(*byte);
+            if self.pos <= MAX_POINTER_OFFSET {
+                self.names.insert(suffix, self.pos as u16);
             }
+
+            self.write_label(labels[i])?;
+        }
+
+        self.write_u8(0)?;
+
+        return Ok(());
+    }
+
+    // Writes the name in full, for RDATA that must not be compressed (RFC 2782)
+    pub fn write_uncompressed_qname(&mut self, qname: &str) -> Result<(), DnsError> {
+        for label in qname.split('.').filter(|label| !label.is_empty()) {
+            self.write_label(label)?;
         }
 
-        self.write_u8(0);
+        self.write_u8(0)?;
+
+        return Ok(());
     }
-}
\ No newline at end of file
+
+    fn write_label(&mut self, label: &str) -> Result<(), DnsError> {
+        if label.len() > 63 {
+            return Err(DnsError::LabelTooLong);
+        }
+
+        self.write_u8(label.len() as u8)?;
+        self.write_bytes(label.as_bytes())?;
+
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_qname(data: &[u8], pos: usize) -> Result<String, DnsError> {
+        let mut buffer = PacketBuffer::from_bytes(data);
+        buffer.set_pos(pos);
+        return buffer.get_qname();
+    }
+
+    #[test]
+    fn pointers_have_to_point_backwards() {
+        // A pointer to itself
+        assert_eq!(read_qname(&[0xC0, 0x00], 0), Err(DnsError::PointerLoop));
+
+        // Two names pointing at each other
+        let data = [1, b'a', 0xC0, 0x04, 0xC0, 0x00];
+        assert_eq!(read_qname(&data, 4), Err(DnsError::PointerLoop));
+
+        // A pointer ahead of the name
+        let data = [0xC0, 0x02, 1, b'a', 0];
+        assert_eq!(read_qname(&data, 0), Err(DnsError::PointerLoop));
+    }
+
+    #[test]
+    fn long_pointer_chains_are_followed() {
+        let mut buffer = PacketBuffer::with_limit(MAX_PACKET_SIZE);
+        let mut qname  = "example.com".to_string();
+        let mut pos    = 0;
+        for i in 0..20 {
+            qname = format!("l{}.{}", i, qname);
+            pos   = buffer.get_pos();
+            buffer.write_qname(&qname).unwrap();
+        }
+
+        assert_eq!(read_qname(buffer.get_data(), pos), Ok(qname));
+    }
+
+    #[test]
+    fn oversized_labels_and_names_are_refused() {
+        let mut data = vec![64];
+        data.extend_from_slice(&[b'a'; 64]);
+        data.push(0);
+        assert_eq!(read_qname(&data, 0), Err(DnsError::LabelTooLong));
+
+        // Four labels of 63 are 257 bytes on the wire
+        let mut data = Vec::new();
+        for _ in 0..4 {
+            data.push(63);
+            data.extend_from_slice(&[b'a'; 63]);
+        }
+        data.push(0);
+        assert_eq!(read_qname(&data, 0), Err(DnsError::LabelTooLong));
+
+        // Three of them and one of 61 are just 255
+        data[3 * 64] = 61;
+        data.drain(3 * 64 + 62..4 * 64);
+        assert_eq!(read_qname(&data, 0).map(|qname| qname.len()), Ok(253));
+
+        let mut buffer = PacketBuffer::with_limit(MAX_PACKET_SIZE);
+        assert_eq!(buffer.write_qname(&"a".repeat(64)), Err(DnsError::LabelTooLong));
+    }
+
+    #[test]
+    fn truncated_names_are_refused() {
+        assert_eq!(read_qname(&[3, b'c', b'o'], 0), Err(DnsError::EndOfBuffer));
+        assert_eq!(read_qname(&[3, b'c', b'o', b'm'], 0), Err(DnsError::EndOfBuffer));
+        assert_eq!(read_qname(&[1, b'a', 0xC0], 0), Err(DnsError::EndOfBuffer));
+    }
+}
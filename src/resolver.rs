@@ -0,0 +1,738 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr, TcpStream, UdpSocket};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::dns_cache::{in_bailiwick, is_delegation, DnsCache};
+use crate::dns_class::DnsClass;
+use crate::dns_packet::DnsPacket;
+use crate::dns_query_type::QueryType;
+use crate::dns_question::DnsQuestion;
+use crate::dns_record::DnsRecord;
+use crate::dns_result_code::ResultCode;
+use crate::dns_tcp;
+use crate::forwarder::{find_zone, ForwardZone, Forwarder, ZoneTarget};
+use crate::packet_buffer::{PacketBuffer, EDNS_PACKET_SIZE, MAX_PACKET_SIZE, UDP_PACKET_SIZE};
+use crate::server_stats::ServerStats;
+
+// How many lookups for nameserver addresses may be nested inside one another
+const MAX_NESTED_LOOKUPS: usize = 8;
+
+// Longest CNAME chain we follow before giving up on the name
+pub const MAX_CNAME_CHAIN: usize = 8;
+
+// Where recursion starts and how patient it is with upstream servers
+#[derive(Clone)]
+pub struct ResolverConfig {
+    pub root_servers:    Vec<IpAddr>,
+    pub upstream_port:   u16,
+    pub timeout:         Duration, // Per attempt
+    pub retries:         u32,      // Extra attempts against the same server before moving on
+    pub recursion_limit: u32,      // Referrals followed for a single name
+    pub max_outstanding: usize,    // Resolutions allowed to wait on upstream servers at once
+    pub cache_size:      usize,
+}
+
+// A resolution some client started, which everyone asking the same question waits on
+struct InFlight {
+    result: Mutex<Option<Result<DnsPacket, ()>>>,
+    done:   Condvar,
+}
+
+// Held by whoever resolves an in-flight question. Dropping it, also when the
+// resolution panics, takes the question out of the in-flight map and wakes up
+// everyone waiting on it, with an error when there is no result.
+struct Leader<'a> {
+    resolver:  &'a Resolver,
+    key:       (String, QueryType),
+    in_flight: Arc<InFlight>,
+}
+
+impl Drop for Leader<'_> {
+    fn drop(&mut self) {
+        // Poisoned locks are used anyway, a second panic while unwinding would abort
+        self.resolver.in_flight.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.key);
+
+        let mut result = self.in_flight.result.lock().unwrap_or_else(PoisonError::into_inner);
+        if result.is_none() {
+            *result = Some(Err(()));
+        }
+
+        self.in_flight.done.notify_all();
+    }
+}
+
+// State of one client question as it works its way through nested lookups
+struct Resolution {
+    // Nameservers further up the stack whose addresses are being looked up, so zones
+    // that can only be reached through each other are caught
+    pending:  Vec<String>,
+    // Whether this counts against the outstanding recursion limit, which happens the
+    // first time the cache can't answer and covers every nested lookup
+    has_slot: bool,
+}
+
+// Shared by every worker thread. The cache and server stats are only locked for
+// single operations, never across an upstream query.
+pub struct Resolver {
+    config:      ResolverConfig,
+    forwarder:   Option<Forwarder>, // Replaces recursion from the roots when set
+    zones:       Vec<ForwardZone>,  // Conditional forwarding, ahead of everything else
+    cache:       Mutex<DnsCache>,
+    stats:       Mutex<ServerStats>,
+    in_flight:   Mutex<HashMap<(String, QueryType), Arc<InFlight>>>,
+    outstanding: AtomicUsize,
+}
+
+impl Resolver {
+    pub fn new(config: ResolverConfig, forwarder: Option<Forwarder>, zones: Vec<ForwardZone>) -> Self {
+        Self {
+            cache:       Mutex::new(DnsCache::new(config.cache_size)),
+            config:      config,
+            forwarder:   forwarder,
+            zones:       zones,
+            stats:       Mutex::new(ServerStats::new()),
+            in_flight:   Mutex::new(HashMap::new()),
+            outstanding: AtomicUsize::new(0),
+        }
+    }
+
+    // Entry point for client questions. When the same question is already being
+    // resolved we wait for that result instead of asking upstream again.
+    pub fn resolve(&self, qname: &str, qtype: QueryType) -> Result<DnsPacket, ()> {
+        let key = (qname.to_lowercase(), qtype);
+
+        let (in_flight, is_leader) = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            match in_flight.get(&key) {
+                Some(existing) => (Arc::clone(existing), false),
+                None           => {
+                    let created = Arc::new(InFlight {
+                        result: Mutex::new(None),
+                        done:   Condvar::new(),
+                    });
+
+                    in_flight.insert(key.clone(), Arc::clone(&created));
+                    (created, true)
+                }
+            }
+        };
+
+        if !is_leader {
+            debug!("Waiting on in-flight resolution of {} {}", qtype, qname);
+
+            // Bounded, so a leader that never finishes can't hold up the workers waiting on it
+            let result = in_flight.result.lock().unwrap();
+            let (result, wait) = in_flight.done.wait_timeout_while(result, self.config.timeout, |result| result.is_none()).unwrap();
+            if wait.timed_out() {
+                warn!("Gave up waiting on in-flight resolution of {} {}", qtype, qname);
+                return Err(());
+            }
+
+            return result.clone().unwrap();
+        }
+
+        let leader = Leader {
+            resolver:  self,
+            key:       key,
+            in_flight: Arc::clone(&in_flight),
+        };
+
+        let mut resolution = Resolution {
+            pending:  Vec::new(),
+            has_slot: false,
+        };
+
+        let result = self.recursive_resolver(qname, qtype, &mut resolution);
+        if resolution.has_slot {
+            self.outstanding.fetch_sub(1, Ordering::SeqCst);
+        }
+
+        *in_flight.result.lock().unwrap() = Some(result.clone());
+        drop(leader);
+
+        return result;
+    }
+
+    pub fn check_upstreams(&self) {
+        if let Some(forwarder) = &self.forwarder {
+            forwarder.check_health(&self.config);
+        }
+
+        for zone in &self.zones {
+            if let ZoneTarget::Forward(forwarder) = &zone.target {
+                forwarder.check_health(&self.config);
+            }
+        }
+    }
+
+    fn acquire_slot(&self) -> bool {
+        let max      = self.config.max_outstanding;
+        let acquired = self.outstanding
+                           .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| (count < max).then_some(count + 1))
+                           .is_ok();
+
+        if !acquired {
+            warn!("Too many outstanding recursions, refusing to start another");
+        }
+
+        return acquired;
+    }
+
+    // Resolves qname, restarting at the target whenever the answer is an alias. The answer
+    // section of the result holds the whole CNAME chain followed by the final RRset.
+    fn recursive_resolver(&self, qname: &str, qtype: QueryType, resolution: &mut Resolution) -> Result<DnsPacket, ()> {
+        let mut chain: Vec<DnsRecord> = Vec::new();
+        let mut name                  = qname.to_lowercase();
+
+        loop {
+            let mut result = self.resolve_name(&name, qtype, resolution)?;
+            let answers    = std::mem::take(&mut result.answer_section);
+
+            // The server may have followed part of the chain already, use whatever it
+            // included before asking again
+            let mut first = true;
+            loop {
+                let records: Vec<DnsRecord> = answers.iter()
+                                                     .filter(|record| record.get_domain().eq_ignore_ascii_case(&name))
+                                                     .cloned()
+                                                     .collect();
+
+                if records.is_empty() && !first {
+                    break;
+                }
+
+                let is_answered = qtype == QueryType::CNAME || records.iter().any(|record| record.get_qtype() == qtype);
+                let target      = records.iter().find_map(|record| match record {
+                    DnsRecord::CNAME { host, .. } if !is_answered => Some(host.to_lowercase()),
+                    _                                             => None,
+                });
+
+                chain.extend(records);
+
+                let Some(target) = target else {
+                    result.answer_section = chain;
+                    return Ok(result);
+                };
+
+                if chain.iter().any(|record| record.get_domain().eq_ignore_ascii_case(&target)) {
+                    warn!("CNAME loop at {} while resolving {}", target, qname);
+                    return Err(());
+                }
+
+                if chain.len() >= MAX_CNAME_CHAIN {
+                    warn!("CNAME chain for {} is too long", qname);
+                    return Err(());
+                }
+
+                name  = target;
+                first = false;
+            }
+        }
+    }
+
+    // Resolves a single name without following aliases, starting from the cache
+    fn resolve_name(&self, qname: &str, qtype: QueryType, resolution: &mut Resolution) -> Result<DnsPacket, ()> {
+        if let Some(answers) = self.cache.lock().unwrap().lookup(qname, qtype, DnsClass::IN) {
+            debug!("Cache hit for {} {}", qtype, qname);
+
+            let mut result        = DnsPacket::new();
+            result.answer_section = answers;
+            return Ok(result);
+        }
+
+        // A cached alias answers every type at the name
+        if qtype != QueryType::CNAME {
+            if let Some(answers) = self.cache.lock().unwrap().lookup(qname, QueryType::CNAME, DnsClass::IN) {
+                debug!("Cache hit for CNAME {}", qname);
+
+                let mut result        = DnsPacket::new();
+                result.answer_section = answers;
+                return Ok(result);
+            }
+        }
+
+        if let Some((response_code, soa)) = self.cache.lock().unwrap().lookup_negative(qname, qtype, DnsClass::IN) {
+            debug!("Negative cache hit for {} {}", qtype, qname);
+
+            let mut result              = DnsPacket::new();
+            result.header.response_code = response_code;
+            result.authority_section.push(soa);
+            return Ok(result);
+        }
+
+        // Past this point we need upstream servers, which is what the limit is for
+        if !resolution.has_slot {
+            if !self.acquire_slot() {
+                return Err(());
+            }
+
+            resolution.has_slot = true;
+        }
+
+        // The most specific conditional forwarding entry wins over the global forwarder
+        // and over recursion from the roots. Its servers are trusted for its zone only,
+        // the global forwarder for everything. That is about what goes into the cache,
+        // the answer goes to the client whole, CNAME chains out of the zone included.
+        let forwarder = match find_zone(&self.zones, qname) {
+            Some(ForwardZone { name, target: ZoneTarget::Forward(forwarder) }) => Some((name.as_str(), forwarder)),
+            Some(ForwardZone { name, target: ZoneTarget::Stub(servers) })      => {
+                return self.iterate(qname, qtype, name.clone(), servers.clone(), resolution);
+            },
+            None => self.forwarder.as_ref().map(|forwarder| ("", forwarder)),
+        };
+
+        if let Some((zone_cut, forwarder)) = forwarder {
+            let result = forwarder.forward(qname, qtype, &self.config)?;
+            self.cache.lock().unwrap().store_packet(&result, zone_cut);
+            return Ok(result);
+        }
+
+        // Skip as much of the delegation chain as the cache still covers
+        let (zone_cut, nameservers) = self.cache.lock().unwrap().find_zone_cut(qname)
+                                          .unwrap_or_else(|| (String::new(), self.config.root_servers.clone()));
+
+        return self.iterate(qname, qtype, zone_cut, self.with_upstream_port(&nameservers), resolution);
+    }
+
+    // Follows referrals down from `nameservers`, the servers of `zone_cut`, until some
+    // server answers the question
+    fn iterate(&self, qname: &str, qtype: QueryType, mut zone_cut: String, mut nameservers: Vec<SocketAddr>, resolution: &mut Resolution) -> Result<DnsPacket, ()> {
+        for _ in 0..self.config.recursion_limit {
+            // Every server of the zone failed us
+            let Some(server) = self.stats.lock().unwrap().choose(&nameservers) else {
+                return Err(());
+            };
+
+            debug!("attempting lookup of {} {} with ns {}", qtype, qname, server);
+
+            let started    = Instant::now();
+            let mut result = match lookup((server.ip(), server.port()), qname, qtype, false, &self.config) {
+                Ok(result) => result,
+                Err(_)     => {
+                    warn!("No response from {}, trying another server", server);
+                    self.stats.lock().unwrap().record_timeout(server, self.config.timeout);
+                    nameservers.retain(|addr| *addr != server);
+                    continue;
+                }
+            };
+
+            self.stats.lock().unwrap().record_rtt(server, started.elapsed());
+
+            // A server that can't or won't answer for the zone is as good as unreachable,
+            // and so is one answering with an error we don't know
+            if matches!(result.header.response_code, ResultCode::SERVFAIL | ResultCode::REFUSED | ResultCode::NOTIMP | ResultCode::UNKNOWN(_)) {
+                warn!("{} answered {:?}, trying another server", server, result.header.response_code);
+                nameservers.retain(|addr| *addr != server);
+                continue;
+            }
+
+            self.cache.lock().unwrap().store_packet(&result, &zone_cut);
+
+            // Answers the server has no authority over are dropped, not passed on
+            result.answer_section = in_bailiwick(&result, &zone_cut);
+            if !result.answer_section.is_empty() {
+                return Ok(result);
+            }
+
+            // NXDOMAIN, or NODATA proven by the zone's SOA, is a final answer too
+            let is_nodata = result.authority_section
+                                  .iter()
+                                  .any(|record| matches!(record, DnsRecord::SOA { .. }));
+
+            if matches!(result.header.response_code, ResultCode::NXDOMAIN) || is_nodata {
+                return Ok(result);
+            }
+
+            // A referral has to lead further down towards qname, anything else would let
+            // the server take over zones it isn't responsible for
+            let delegation = result.authority_section.iter().find_map(|record| match record {
+                DnsRecord::NS { domain, .. } if is_delegation(qname, domain, &zone_cut) => Some(domain.to_lowercase()),
+                _                                                                        => None,
+            });
+
+            let Some(delegation) = delegation else {
+                warn!("{} sent a referral that doesn't lead below {:?}, trying another server", server, zone_cut);
+                nameservers.retain(|addr| *addr != server);
+                continue;
+            };
+
+            // Follow the referral to the nameservers of the delegated zone. Without glue
+            // their names are out of bailiwick and have to be resolved on their own.
+            zone_cut = delegation;
+
+            let mut addrs = result.get_glue();
+            if addrs.is_empty() {
+                addrs = self.resolve_nameservers(&result, resolution);
+            }
+
+            nameservers = self.with_upstream_port(&addrs);
+        }
+
+        return Err(());
+    }
+
+    fn with_upstream_port(&self, addrs: &[IpAddr]) -> Vec<SocketAddr> {
+        return addrs.iter()
+                    .map(|addr| SocketAddr::new(*addr, self.config.upstream_port))
+                    .collect();
+    }
+
+    // Looks up the addresses of the nameservers in a referral, stopping at the first one
+    // that resolves
+    fn resolve_nameservers(&self, referral: &DnsPacket, resolution: &mut Resolution) -> Vec<IpAddr> {
+        let mut addrs = Vec::new();
+
+        for record in &referral.authority_section {
+            let DnsRecord::NS { host, .. } = record else {
+                continue;
+            };
+
+            let host = host.to_lowercase();
+            if resolution.pending.contains(&host) {
+                warn!("Skipping nameserver {}, its lookup depends on itself", host);
+                continue;
+            }
+
+            if resolution.pending.len() >= MAX_NESTED_LOOKUPS {
+                warn!("Skipping nameserver {}, {} nameserver lookups are already nested", host, MAX_NESTED_LOOKUPS);
+                continue;
+            }
+
+            debug!("Resolving nameserver {} without glue", host);
+            resolution.pending.push(host.clone());
+
+            for qtype in [QueryType::A, QueryType::AAAA] {
+                let Ok(result) = self.recursive_resolver(&host, qtype, resolution) else {
+                    continue;
+                };
+
+                for answer in result.answer_section {
+                    match answer {
+                        DnsRecord::A { addr, .. }    => addrs.push(IpAddr::V4(addr)),
+                        DnsRecord::AAAA { addr, .. } => addrs.push(IpAddr::V6(addr)),
+                        _                            => (),
+                    }
+                }
+            }
+
+            resolution.pending.pop();
+
+            if !addrs.is_empty() {
+                break;
+            }
+        }
+
+        return addrs;
+    }
+}
+
+// Recursion is only desired from forwarders, servers we iterate over are asked for
+// what they know themselves
+pub fn lookup(server: (IpAddr, u16), qname: &str, qtype: QueryType, recursion_desired: bool, config: &ResolverConfig) -> Result<DnsPacket, ()> {
+    let response = send_query(server, qname, qtype, recursion_desired, true, config)?;
+
+    // Servers that predate EDNS reject the OPT record outright (RFC 6891 7)
+    let rejected_edns = matches!(response.header.response_code, ResultCode::FORMERR | ResultCode::NOTIMP);
+    if rejected_edns && response.get_opt().is_none() {
+        debug!("{:?} does not support EDNS, retrying without it", server);
+        return send_query(server, qname, qtype, recursion_desired, false, config);
+    }
+
+    return Ok(response);
+}
+
+// Asks the server again on timeouts, each time from a fresh port and with a fresh ID
+fn send_query(server: (IpAddr, u16), qname: &str, qtype: QueryType, recursion_desired: bool, edns: bool, config: &ResolverConfig) -> Result<DnsPacket, ()> {
+    for attempt in 0..=config.retries {
+        if attempt > 0 {
+            debug!("Retrying {} {} with {:?}, attempt {}", qtype, qname, server, attempt + 1);
+        }
+
+        if let Ok(response) = query_udp(server, qname, qtype, recursion_desired, edns, config.timeout) {
+            return Ok(response);
+        }
+    }
+
+    return Err(());
+}
+
+fn query_udp(server: (IpAddr, u16), qname: &str, qtype: QueryType, recursion_desired: bool, edns: bool, timeout: Duration) -> Result<DnsPacket, ()> {
+    let local_addr = match server.0 {
+        IpAddr::V4(_) => "0.0.0.0",
+        IpAddr::V6(_) => "::",
+    };
+
+    // Port 0 lets the OS pick a random ephemeral port, which together with the random
+    // ID is what makes forging a reply hard
+    let socket = UdpSocket::bind((local_addr, 0)).map_err(|_| ())?;
+
+    let mut packet                  = DnsPacket::new();
+    packet.header.packet_identifier = rand::random();
+    packet.header.question_count    = 1;
+    packet.header.recursion_desired = recursion_desired;
+    packet.question_section
+          .push(DnsQuestion::new(qname.to_string(), qtype, DnsClass::IN));
+
+    if edns {
+        packet.additional_section.push(edns_record(false));
+    }
+
+    let mut request_buffer = PacketBuffer::with_limit(UDP_PACKET_SIZE);
+    packet.write_packet_to_buffer(&mut request_buffer).map_err(|_| ())?;
+
+    socket.send_to(request_buffer.get_data(), server).map_err(|_| ())?;
+
+    // Anything that isn't the reply to this exact query is dropped, and we keep
+    // listening until the timeout runs out
+    let deadline = Instant::now() + timeout;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(());
+        }
+
+        socket.set_read_timeout(Some(remaining)).map_err(|_| ())?;
+
+        let mut data   = [0; MAX_PACKET_SIZE];
+        let (len, src) = socket.recv_from(&mut data).map_err(|_| ())?;
+        if src != SocketAddr::from(server) {
+            warn!("Discarding reply from unexpected address {}", src);
+            continue;
+        }
+
+        let mut response_buffer = PacketBuffer::from_bytes(&data[..len]);
+        let response = match DnsPacket::get_packet_from_buffer(&mut response_buffer) {
+            Ok(response) if response.is_response_to(&packet) => response,
+            _ => {
+                warn!("Discarding reply from {} that doesn't match the query", src);
+                continue;
+            }
+        };
+
+        if response.header.truncated_message {
+            debug!("Truncated response from {:?}, retrying over TCP", server);
+            return lookup_tcp(server, &packet, request_buffer.get_data(), timeout);
+        }
+
+        return Ok(response);
+    }
+}
+
+fn lookup_tcp(server: (IpAddr, u16), packet: &DnsPacket, request: &[u8], timeout: Duration) -> Result<DnsPacket, ()> {
+    let mut stream = TcpStream::connect_timeout(&server.into(), timeout).map_err(|_| ())?;
+    let _          = stream.set_read_timeout(Some(timeout));
+
+    dns_tcp::write_message(&mut stream, request).map_err(|_| ())?;
+
+    let mut response_buffer = match dns_tcp::read_message(&mut stream) {
+        Ok(Some(buffer)) => buffer,
+        _                => return Err(()),
+    };
+
+    let response = DnsPacket::get_packet_from_buffer(&mut response_buffer).map_err(|_| ())?;
+    if !response.is_response_to(packet) {
+        warn!("Discarding TCP reply from {:?} that doesn't match the query", server);
+        return Err(());
+    }
+
+    return Ok(response);
+}
+
+// The OPT record we attach to everything we send, advertising our UDP buffer size
+pub fn edns_record(dnssec_ok: bool) -> DnsRecord {
+    return DnsRecord::OPT {
+        payload_size:   EDNS_PACKET_SIZE as u16,
+        extended_rcode: 0,
+        version:        0,
+        dnssec_ok:      dnssec_ok,
+        options:        Vec::new(),
+    };
+}
+
+#[cfg(test)]
+pub mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::forwarder::ForwardStrategy;
+
+    // An upstream server on a port of its own, answering each question with what
+    // `answer` makes of it for as long as the tests run. None leaves it unanswered.
+    pub fn upstream(answer: impl Fn(&DnsQuestion) -> Option<DnsPacket> + Send + 'static) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr   = socket.local_addr().unwrap();
+
+        thread::spawn(move || loop {
+            let mut data       = [0; MAX_PACKET_SIZE];
+            let Ok((len, src)) = socket.recv_from(&mut data) else {
+                return;
+            };
+
+            let request = DnsPacket::get_packet_from_buffer(&mut PacketBuffer::from_bytes(&data[..len])).unwrap();
+
+            let Some(mut response) = answer(&request.question_section[0]) else {
+                continue;
+            };
+
+            response.header.packet_identifier = request.header.packet_identifier;
+            response.header.query_response    = true;
+            response.question_section         = request.question_section.clone();
+
+            let mut buffer = PacketBuffer::with_limit(MAX_PACKET_SIZE);
+            response.write_packet_to_buffer(&mut buffer).unwrap();
+            let _ = socket.send_to(buffer.get_data(), src);
+        });
+
+        return addr;
+    }
+
+    // An authoritative server for every name, answering from `records`, or with the
+    // CNAME when a name has one
+    pub fn authority(records: Vec<DnsRecord>) -> SocketAddr {
+        return upstream(move |question| {
+            let mut result                     = DnsPacket::new();
+            result.header.authoritative_answer = true;
+            result.answer_section              = records.iter()
+                                                        .filter(|record| record.get_domain().eq_ignore_ascii_case(&question.qname))
+                                                        .filter(|record| [question.qtype, QueryType::CNAME].contains(&record.get_qtype()))
+                                                        .cloned()
+                                                        .collect();
+            Some(result)
+        });
+    }
+
+    // Recursion from `root`, quick to give up on servers that don't answer
+    pub fn config(root: SocketAddr) -> ResolverConfig {
+        return ResolverConfig {
+            root_servers:    vec![root.ip()],
+            upstream_port:   root.port(),
+            timeout:         Duration::from_millis(500),
+            retries:         0,
+            recursion_limit: 8,
+            max_outstanding: 16,
+            cache_size:      1000,
+        };
+    }
+
+    pub fn a(name: &str, last: u8) -> DnsRecord {
+        return DnsRecord::A { domain: name.to_string(), addr: [192, 0, 2, last].into(), class: DnsClass::IN, ttl: 300 };
+    }
+
+    pub fn cname(name: &str, host: &str) -> DnsRecord {
+        return DnsRecord::CNAME { domain: name.to_string(), host: host.to_string(), class: DnsClass::IN, ttl: 300 };
+    }
+
+    fn answers(result: Result<DnsPacket, ()>) -> Result<Vec<String>, ()> {
+        return result.map(|result| result.answer_section.iter().map(|record| record.to_string()).collect());
+    }
+
+    // c0 to c<length - 1> alias one another in turn and the last one has an address
+    fn chain(length: usize) -> Vec<DnsRecord> {
+        let mut records: Vec<DnsRecord> = (0..length).map(|n| cname(&format!("c{}.test", n), &format!("c{}.test", n + 1))).collect();
+        records.push(a(&format!("c{}.test", length), 1));
+        return records;
+    }
+
+    #[test]
+    fn cname_chain_is_followed() {
+        let resolver = Resolver::new(config(authority(chain(3))), None, Vec::new());
+
+        assert_eq!(answers(resolver.resolve("c0.test", QueryType::A)), Ok(vec![
+            "c0.test. 300 IN CNAME c1.test.".to_string(),
+            "c1.test. 300 IN CNAME c2.test.".to_string(),
+            "c2.test. 300 IN CNAME c3.test.".to_string(),
+            "c3.test. 300 IN A 192.0.2.1".to_string(),
+        ]));
+
+        // Asked for the alias itself, there is nothing to follow
+        assert_eq!(answers(resolver.resolve("c1.test", QueryType::CNAME)), Ok(vec!["c1.test. 300 IN CNAME c2.test.".to_string()]));
+    }
+
+    #[test]
+    fn cname_loop_is_caught() {
+        let records  = vec![cname("a.test", "b.test"), cname("b.test", "c.test"), cname("c.test", "A.test")];
+        let resolver = Resolver::new(config(authority(records)), None, Vec::new());
+
+        assert_eq!(answers(resolver.resolve("a.test", QueryType::A)), Err(()));
+        assert_eq!(answers(resolver.resolve("b.test", QueryType::A)), Err(()));
+
+        // A name aliased to itself
+        let resolver = Resolver::new(config(authority(vec![cname("self.test", "self.test")])), None, Vec::new());
+        assert_eq!(answers(resolver.resolve("self.test", QueryType::A)), Err(()));
+    }
+
+    #[test]
+    fn cname_chain_is_capped() {
+        let resolver = Resolver::new(config(authority(chain(MAX_CNAME_CHAIN))), None, Vec::new());
+
+        // The longest chain followed, then one alias more
+        assert_eq!(answers(resolver.resolve("c1.test", QueryType::A)).map(|answers| answers.len()), Ok(MAX_CNAME_CHAIN));
+        assert_eq!(answers(resolver.resolve("c0.test", QueryType::A)), Err(()));
+    }
+
+    #[test]
+    fn followers_dont_wait_forever() {
+        let resolver = Resolver::new(config(authority(vec![a("www.test", 1)])), None, Vec::new());
+
+        // Someone else is resolving the question, as far as anyone can tell
+        let in_flight = Arc::new(InFlight { result: Mutex::new(None), done: Condvar::new() });
+        let leader    = Leader { resolver: &resolver, key: ("www.test".to_string(), QueryType::A), in_flight: Arc::clone(&in_flight) };
+        resolver.in_flight.lock().unwrap().insert(leader.key.clone(), in_flight);
+
+        let started = Instant::now();
+        assert!(resolver.resolve("www.test", QueryType::A).is_err());
+        assert!(started.elapsed() >= resolver.config.timeout);
+
+        // A leader that goes away without a result wakes up whoever is waiting
+        thread::scope(|scope| {
+            let follower = scope.spawn(|| resolver.resolve("www.test", QueryType::A));
+            thread::sleep(Duration::from_millis(50));
+
+            let started = Instant::now();
+            drop(leader);
+            assert!(follower.join().unwrap().is_err());
+            assert!(started.elapsed() < resolver.config.timeout);
+        });
+
+        // And the question can be asked again
+        assert_eq!(answers(resolver.resolve("www.test", QueryType::A)), Ok(vec!["www.test. 300 IN A 192.0.2.1".to_string()]));
+    }
+
+    #[test]
+    fn forwarded_cname_chain_is_kept() {
+        // Public recursion would find nothing, and counts how often it is asked
+        let asked = Arc::new(AtomicUsize::new(0));
+        let root  = {
+            let asked = Arc::clone(&asked);
+            upstream(move |_| {
+                asked.fetch_add(1, Ordering::SeqCst);
+                Some(DnsPacket::new())
+            })
+        };
+
+        // The internal resolver follows the alias out of its zone itself
+        let internal = upstream(|_| {
+            let mut result        = DnsPacket::new();
+            result.answer_section = vec![cname("www.corp.test", "app.cloud.test"), a("app.cloud.test", 7)];
+            Some(result)
+        });
+        let zone     = ForwardZone {
+            name:   "corp.test".to_string(),
+            target: ZoneTarget::Forward(Forwarder::new(vec![internal], ForwardStrategy::RoundRobin)),
+        };
+
+        let resolver = Resolver::new(config(root), None, vec![zone]);
+        assert_eq!(answers(resolver.resolve("www.corp.test", QueryType::A)), Ok(vec![
+            "www.corp.test. 300 IN CNAME app.cloud.test.".to_string(),
+            "app.cloud.test. 300 IN A 192.0.2.7".to_string(),
+        ]));
+        assert_eq!(asked.load(Ordering::SeqCst), 0);
+
+        // Only what is inside the zone was cached
+        let mut cache = resolver.cache.lock().unwrap();
+        assert!(cache.lookup("www.corp.test", QueryType::CNAME, DnsClass::IN).is_some());
+        assert!(cache.lookup("app.cloud.test", QueryType::A, DnsClass::IN).is_none());
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use rand::Rng;
+
+// Chance of picking a random server instead of the fastest, so a server that was
+// slow once gets measured again
+const EXPLORE_PROBABILITY: f64 = 0.05;
+
+// Weight of the newest sample in the smoothed RTT
+const RTT_WEIGHT: f64 = 0.3;
+
+// Servers that weren't picked have their SRTT shrink a little every time, so even
+// heavily penalized servers eventually get another chance (the BIND approach)
+const UNSELECTED_DECAY: f64 = 0.98;
+
+// Ceiling for the penalty applied on timeouts
+const MAX_SRTT_MS: f64 = 10_000.0;
+
+// Addresses remembered at once, past this the least recently used are forgotten
+const MAX_SERVERS: usize = 10_000;
+
+struct ServerEntry {
+    srtt_ms:   f64,
+    last_used: u64, // Key in `used`
+}
+
+// Smoothed round trip times of upstream server addresses we have talked to. The
+// resolver keeps one for every nameserver from the roots down, each forwarder one for
+// its upstreams.
+pub struct ServerStats {
+    servers: HashMap<SocketAddr, ServerEntry>,
+    used:    BTreeMap<u64, SocketAddr>, // Least recently used first
+    uses:    u64,                       // Counts every use, so keys in `used` are unique
+}
+
+impl ServerStats {
+    pub fn new() -> Self {
+        Self {
+            servers: HashMap::new(),
+            used:    BTreeMap::new(),
+            uses:    0,
+        }
+    }
+
+    // Picks the server to query from a nameserver set
+    pub fn choose(&mut self, candidates: &[SocketAddr]) -> Option<SocketAddr> {
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let mut rng = rand::thread_rng();
+
+        // Servers we've never measured start out with a small random SRTT, so they
+        // get tried early and in no particular order
+        for addr in candidates {
+            self.entry(*addr, || rng.gen_range(0.0..20.0));
+        }
+
+        let chosen = if rng.gen_bool(EXPLORE_PROBABILITY) {
+            candidates[rng.gen_range(0..candidates.len())]
+        } else {
+            *candidates.iter()
+                       .min_by(|a, b| self.servers[a].srtt_ms.total_cmp(&self.servers[b].srtt_ms))
+                       .unwrap()
+        };
+
+        for addr in candidates {
+            if *addr != chosen {
+                self.servers.get_mut(addr).unwrap().srtt_ms *= UNSELECTED_DECAY;
+            }
+        }
+
+        return Some(chosen);
+    }
+
+    // Every candidate in the order to try them: the one choose() picks, then the rest
+    // fastest first
+    pub fn rank(&mut self, candidates: &[SocketAddr]) -> Vec<SocketAddr> {
+        let Some(chosen) = self.choose(candidates) else {
+            return Vec::new();
+        };
+
+        let mut rest: Vec<SocketAddr> = candidates.iter().copied().filter(|addr| *addr != chosen).collect();
+        rest.sort_by(|a, b| self.servers[a].srtt_ms.total_cmp(&self.servers[b].srtt_ms));
+        rest.insert(0, chosen);
+
+        return rest;
+    }
+
+    pub fn record_rtt(&mut self, addr: SocketAddr, rtt: Duration) {
+        let sample = rtt.as_secs_f64() * 1000.0;
+        let srtt   = self.entry(addr, || sample);
+
+        *srtt = (1.0 - RTT_WEIGHT) * *srtt + RTT_WEIGHT * sample;
+    }
+
+    // A server that didn't answer is treated as at least as slow as the timeout, and
+    // twice as slow as before if it was already that slow
+    pub fn record_timeout(&mut self, addr: SocketAddr, timeout: Duration) {
+        let timeout_ms = timeout.as_secs_f64() * 1000.0;
+        let srtt       = self.entry(addr, || timeout_ms);
+
+        *srtt = (*srtt * 2.0).max(timeout_ms).min(MAX_SRTT_MS);
+    }
+
+    // The SRTT of the server, starting at `initial` when it's new to us. Either way it
+    // counts as used just now.
+    fn entry(&mut self, addr: SocketAddr, initial: impl FnOnce() -> f64) -> &mut f64 {
+        match self.servers.get(&addr) {
+            Some(entry) => {
+                self.used.remove(&entry.last_used);
+            },
+            None if self.servers.len() >= MAX_SERVERS => {
+                if let Some((_, oldest)) = self.used.pop_first() {
+                    self.servers.remove(&oldest);
+                }
+            },
+            None => (),
+        }
+
+        self.uses += 1;
+        self.used.insert(self.uses, addr);
+
+        let entry = self.servers.entry(addr).or_insert_with(|| ServerEntry {
+            srtt_ms:   initial(),
+            last_used: 0,
+        });
+
+        entry.last_used = self.uses;
+        return &mut entry.srtt_ms;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn addr(n: usize) -> SocketAddr {
+        return SocketAddr::from((Ipv4Addr::from(n as u32), 53));
+    }
+
+    #[test]
+    fn least_recently_used_are_forgotten() {
+        let mut stats = ServerStats::new();
+        for n in 0..MAX_SERVERS {
+            stats.record_rtt(addr(n), Duration::from_millis(10));
+        }
+
+        // Using the oldest again makes the next one the oldest
+        stats.record_timeout(addr(0), Duration::from_millis(1000));
+        stats.record_rtt(addr(MAX_SERVERS), Duration::from_millis(10));
+
+        assert_eq!(stats.servers.len(), MAX_SERVERS);
+        assert_eq!(stats.used.len(), MAX_SERVERS);
+        assert!(!stats.servers.contains_key(&addr(1)));
+        assert_eq!(stats.servers[&addr(0)].srtt_ms, 1000.0);
+        assert_eq!(stats.used.first_key_value().map(|(_, addr)| *addr), Some(addr(2)));
+    }
+}
@@ -0,0 +1,578 @@
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer};
+use sha2::{Sha256, Sha512};
+
+use crate::dns_class::DnsClass;
+use crate::dns_header::DnsHeader;
+use crate::dns_packet::DnsPacket;
+use crate::dns_question::DnsQuestion;
+use crate::dns_query_type::QueryType;
+use crate::dns_record::DnsRecord;
+use crate::packet_buffer::PacketBuffer;
+
+const TSIG: u16 = 250;
+
+// Seconds our clock and the signer's may be apart (RFC 8945 10)
+const FUDGE: u16 = 300;
+
+// Errors carried in the TSIG record, the header says NOTAUTH (RFC 8945 3)
+pub const BADSIG:  u16 = 16;
+pub const BADKEY:  u16 = 17;
+pub const BADTIME: u16 = 18;
+
+// A response to a signed request may go unsigned for this many messages in a row
+// before the signature chain counts as broken (RFC 8945 5.3.1)
+const MAX_UNSIGNED: usize = 99;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Algorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Self::HmacSha256 => "hmac-sha256",
+            Self::HmacSha512 => "hmac-sha512",
+        }
+    }
+}
+
+// A shared secret named the same on both ends, as found in the config file
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Key {
+    pub name:      String,
+    pub algorithm: Algorithm,
+    #[serde(deserialize_with = "base64_secret")]
+    pub secret:    Vec<u8>,
+}
+
+impl Key {
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        // HMAC takes keys of any length, new_from_slice can't fail
+        match self.algorithm {
+            Algorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            },
+            Algorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(&self.secret).unwrap();
+                mac.update(data);
+                mac.finalize().into_bytes().to_vec()
+            },
+        }
+    }
+
+    // Constant time, so timing doesn't tell how much of a forged MAC was right
+    fn verify(&self, data: &[u8], expected: &[u8]) -> bool {
+        match self.algorithm {
+            Algorithm::HmacSha256 => {
+                let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+                mac.update(data);
+                mac.verify_slice(expected).is_ok()
+            },
+            Algorithm::HmacSha512 => {
+                let mut mac = Hmac::<Sha512>::new_from_slice(&self.secret).unwrap();
+                mac.update(data);
+                mac.verify_slice(expected).is_ok()
+            },
+        }
+    }
+}
+
+fn base64_secret<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let secret = String::deserialize(deserializer)?;
+    return STANDARD.decode(secret.trim())
+                   .map_err(|err| serde::de::Error::custom(format!("secret is not valid base64: {}", err)));
+}
+
+// RDATA of a TSIG record (RFC 8945 4.2)
+struct TsigRecord {
+    key_name:    String,
+    algorithm:   String,
+    time_signed: u64,
+    fudge:       u16,
+    mac:         Vec<u8>,
+    original_id: u16,
+    error:       u16,
+    other:       Vec<u8>,
+}
+
+impl TsigRecord {
+    // TSIG has no variant of its own, so it comes off the wire as an unknown type
+    fn parse(record: &DnsRecord) -> Option<Self> {
+        let DnsRecord::UNKNOWN { ref domain, qtype: TSIG, ref data, class: DnsClass::ANY, .. } = *record else {
+            return None;
+        };
+
+        let mut buffer  = PacketBuffer::from_bytes(data);
+        let algorithm   = buffer.get_qname().ok()?;
+        let time_signed = (buffer.read_u16().ok()? as u64) << 32 | buffer.read_u32().ok()? as u64;
+        let fudge       = buffer.read_u16().ok()?;
+        let mac_size    = buffer.read_u16().ok()?;
+        let mac         = buffer.read_bytes(mac_size as usize).ok()?;
+        let original_id = buffer.read_u16().ok()?;
+        let error       = buffer.read_u16().ok()?;
+        let other_size  = buffer.read_u16().ok()?;
+        let other       = buffer.read_bytes(other_size as usize).ok()?;
+
+        if buffer.get_pos() != data.len() {
+            return None;
+        }
+
+        return Some(Self {
+            key_name:    domain.to_lowercase(),
+            algorithm:   algorithm.to_lowercase(),
+            time_signed: time_signed,
+            fudge:       fudge,
+            mac:         mac,
+            original_id: original_id,
+            error:       error,
+            other:       other,
+        });
+    }
+
+    fn in_time(&self, time: u64) -> bool {
+        return time.abs_diff(self.time_signed) <= self.fudge as u64;
+    }
+}
+
+// The signing state of one exchange: a request and its responses, which may run to
+// many messages for a zone transfer. Each signature covers the one before it.
+pub struct Session {
+    key:       Key,
+    prior_mac: Vec<u8>,
+    signed:    usize,   // Messages we signed so far
+    verified:  usize,   // Messages we checked so far
+    unsigned:  Vec<u8>, // Messages received without a signature since the last one that had it
+    pending:   usize,   // How many of them
+}
+
+impl Session {
+    // For signing a request of our own
+    pub fn new(key: &Key) -> Self {
+        Self {
+            key:       key.clone(),
+            prior_mac: Vec::new(),
+            signed:    0,
+            verified:  0,
+            unsigned:  Vec::new(),
+            pending:   0,
+        }
+    }
+
+    pub fn key_name(&self) -> &str {
+        return &self.key.name;
+    }
+
+    // Appends our TSIG record to the message. The first message carries every TSIG
+    // variable, the ones after it only the timers (RFC 8945 5.3.1).
+    pub fn sign(&mut self, message: &[u8]) -> Vec<u8> {
+        let time      = now();
+        let variables = match self.signed {
+            0 => variables(&self.key, time, 0, &[]),
+            _ => timers(time, FUDGE),
+        };
+
+        let mac    = self.key.mac(&digest(&self.prior_mac, message, &variables));
+        let signed = append_record(message, &self.key, time, &mac, 0, &[]);
+
+        self.prior_mac = mac;
+        self.signed   += 1;
+
+        return signed;
+    }
+
+    // Room the TSIG record takes, to hold back when a message has a size limit
+    pub fn record_size(&self) -> usize {
+        let mac_size = match self.key.algorithm {
+            Algorithm::HmacSha256 => 32,
+            Algorithm::HmacSha512 => 64,
+        };
+
+        return wire_name(&self.key.name).len() + 10 + wire_name(self.key.algorithm.name()).len() + 16 + mac_size;
+    }
+
+    // Checks a response to the request we signed. Messages after the first may come
+    // unsigned, they are then covered by the next signature.
+    pub fn verify(&mut self, raw: &[u8], response: &DnsPacket) -> Result<(), String> {
+        let Some((offset, record)) = find_record(raw, response) else {
+            if self.verified == 0 {
+                return Err("response isn't signed".to_string());
+            }
+
+            self.pending += 1;
+            if self.pending > MAX_UNSIGNED {
+                return Err(format!("more than {} messages in a row aren't signed", MAX_UNSIGNED));
+            }
+
+            self.unsigned.extend_from_slice(raw);
+            return Ok(());
+        };
+
+        if record.key_name != self.key.name || record.algorithm != self.key.algorithm.name() {
+            return Err(format!("response is signed with key {} instead of {}", record.key_name, self.key.name));
+        }
+
+        if record.error != 0 {
+            return Err(format!("primary rejected our signature with TSIG error {}", record.error));
+        }
+
+        let variables = match self.verified {
+            0 => variables_of(&record),
+            _ => timers(record.time_signed, record.fudge),
+        };
+
+        let mut message = self.unsigned.clone();
+        message.extend(unsigned_message(raw, offset, record.original_id));
+
+        if !self.key.verify(&digest(&self.prior_mac, &message, &variables), &record.mac) {
+            return Err("response signature doesn't match".to_string());
+        }
+
+        if !record.in_time(now()) {
+            return Err("response was signed too far from our time".to_string());
+        }
+
+        self.prior_mac = record.mac;
+        self.verified += 1;
+        self.unsigned.clear();
+        self.pending   = 0;
+
+        return Ok(());
+    }
+
+    // Whether the last message received was signed, as the last one of a response
+    // has to be
+    pub fn is_complete(&self) -> bool {
+        return self.pending == 0;
+    }
+}
+
+// Why a signed request can't be trusted. It's answered with the error in a TSIG
+// record, signed only for BADTIME since the other errors mean we can't.
+pub struct Rejection {
+    pub error: u16,
+    key_name:  String,
+    algorithm: String,
+    session:   Option<Box<Session>>,
+}
+
+impl Rejection {
+    pub fn sign(mut self, message: &[u8]) -> Vec<u8> {
+        let time = now();
+
+        let Some(ref mut session) = self.session else {
+            return append_record_named(message, &self.key_name, &self.algorithm, time, &[], self.error, &[]);
+        };
+
+        // BADTIME tells the client our time, so it can see how far off it is
+        let other     = time_bytes(time).to_vec();
+        let variables = variables(&session.key, time, self.error, &other);
+        let mac       = session.key.mac(&digest(&session.prior_mac, message, &variables));
+
+        return append_record(message, &session.key, time, &mac, self.error, &other);
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let error = match self.error {
+            BADSIG => "BADSIG",
+            BADKEY => "BADKEY",
+            _      => "BADTIME",
+        };
+
+        write!(f, "{} for key {}.", error, self.key_name)
+    }
+}
+
+// Checks the signature on a request, if it has one (RFC 8945 5.2). A good signature
+// gives the session to sign the response with.
+pub fn verify_request(raw: &[u8], request: &DnsPacket, keys: &[Key]) -> Result<Option<Session>, Rejection> {
+    let Some((offset, record)) = find_record(raw, request) else {
+        return Ok(None);
+    };
+
+    let mut rejection = Rejection {
+        error:     BADKEY,
+        key_name:  record.key_name.clone(),
+        algorithm: record.algorithm.clone(),
+        session:   None,
+    };
+
+    let Some(key) = keys.iter().find(|key| key.name == record.key_name && key.algorithm.name() == record.algorithm) else {
+        return Err(rejection);
+    };
+
+    let message = unsigned_message(raw, offset, record.original_id);
+    if !key.verify(&digest(&[], &message, &variables_of(&record)), &record.mac) {
+        rejection.error = BADSIG;
+        return Err(rejection);
+    }
+
+    let mut session = Session::new(key);
+    session.prior_mac = record.mac.clone();
+
+    if !record.in_time(now()) {
+        rejection.error   = BADTIME;
+        rejection.session = Some(Box::new(session));
+        return Err(rejection);
+    }
+
+    return Ok(Some(session));
+}
+
+// Where the TSIG record starts and what it holds, when it's the last record of the
+// message as it has to be
+fn find_record(raw: &[u8], packet: &DnsPacket) -> Option<(usize, TsigRecord)> {
+    let record = TsigRecord::parse(packet.additional_section.last()?)?;
+
+    // Everything up to the TSIG record, read again to find where it ends
+    let mut buffer = PacketBuffer::from_bytes(raw);
+    DnsHeader::new().read(&mut buffer).ok()?;
+
+    for _ in 0..packet.question_section.len() {
+        let mut question = DnsQuestion::new(String::new(), QueryType::UNKNOWN(0), DnsClass::IN);
+        question.read(&mut buffer).ok()?;
+    }
+
+    let records = packet.answer_section.len() + packet.authority_section.len() + packet.additional_section.len();
+    for _ in 1..records {
+        DnsRecord::read(&mut buffer).ok()?;
+    }
+
+    return Some((buffer.get_pos(), record));
+}
+
+// The message as it was before the TSIG record was added: without it, one record
+// fewer in ARCOUNT and with the original ID
+fn unsigned_message(raw: &[u8], offset: usize, original_id: u16) -> Vec<u8> {
+    let mut message = raw[..offset].to_vec();
+    let additional  = u16::from_be_bytes([message[10], message[11]]) - 1;
+
+    message[0..2].copy_from_slice(&original_id.to_be_bytes());
+    message[10..12].copy_from_slice(&additional.to_be_bytes());
+
+    return message;
+}
+
+fn digest(prior_mac: &[u8], message: &[u8], variables: &[u8]) -> Vec<u8> {
+    let mut data = Vec::new();
+    if !prior_mac.is_empty() {
+        data.extend_from_slice(&(prior_mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(prior_mac);
+    }
+
+    data.extend_from_slice(message);
+    data.extend_from_slice(variables);
+
+    return data;
+}
+
+// TSIG variables that go into the MAC (RFC 8945 4.3.3)
+fn variables(key: &Key, time: u64, error: u16, other: &[u8]) -> Vec<u8> {
+    return variables_named(&key.name, key.algorithm.name(), time, FUDGE, error, other);
+}
+
+fn variables_of(record: &TsigRecord) -> Vec<u8> {
+    return variables_named(&record.key_name, &record.algorithm, record.time_signed, record.fudge, record.error, &record.other);
+}
+
+fn variables_named(key_name: &str, algorithm: &str, time: u64, fudge: u16, error: u16, other: &[u8]) -> Vec<u8> {
+    let mut data = wire_name(key_name);
+    data.extend_from_slice(&DnsClass::ANY.to_num().to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend(wire_name(algorithm));
+    data.extend(timers(time, fudge));
+    data.extend_from_slice(&error.to_be_bytes());
+    data.extend_from_slice(&(other.len() as u16).to_be_bytes());
+    data.extend_from_slice(other);
+
+    return data;
+}
+
+fn timers(time: u64, fudge: u16) -> Vec<u8> {
+    let mut data = time_bytes(time).to_vec();
+    data.extend_from_slice(&fudge.to_be_bytes());
+    return data;
+}
+
+fn append_record(message: &[u8], key: &Key, time: u64, mac: &[u8], error: u16, other: &[u8]) -> Vec<u8> {
+    return append_record_named(message, &key.name, key.algorithm.name(), time, mac, error, other);
+}
+
+fn append_record_named(message: &[u8], key_name: &str, algorithm: &str, time: u64, mac: &[u8], error: u16, other: &[u8]) -> Vec<u8> {
+    // Names in TSIG are never compressed
+    let mut rdata = wire_name(algorithm);
+    rdata.extend(timers(time, FUDGE));
+    rdata.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(mac);
+    rdata.extend_from_slice(&message[0..2]); // Original ID
+    rdata.extend_from_slice(&error.to_be_bytes());
+    rdata.extend_from_slice(&(other.len() as u16).to_be_bytes());
+    rdata.extend_from_slice(other);
+
+    let mut signed = message.to_vec();
+    signed.extend(wire_name(key_name));
+    signed.extend_from_slice(&TSIG.to_be_bytes());
+    signed.extend_from_slice(&DnsClass::ANY.to_num().to_be_bytes());
+    signed.extend_from_slice(&0u32.to_be_bytes());
+    signed.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    signed.extend(rdata);
+
+    let additional = u16::from_be_bytes([signed[10], signed[11]]) + 1;
+    signed[10..12].copy_from_slice(&additional.to_be_bytes());
+
+    return signed;
+}
+
+// Lowercase and uncompressed, the canonical form MACs are computed over
+fn wire_name(name: &str) -> Vec<u8> {
+    let mut wire = Vec::new();
+    for label in name.split('.').filter(|label| !label.is_empty()) {
+        wire.push(label.len() as u8);
+        wire.extend(label.to_lowercase().bytes());
+    }
+
+    wire.push(0);
+    return wire;
+}
+
+// Time signed is 48 bits
+fn time_bytes(time: u64) -> [u8; 6] {
+    let bytes = time.to_be_bytes();
+    return [bytes[2], bytes[3], bytes[4], bytes[5], bytes[6], bytes[7]];
+}
+
+fn now() -> u64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(algorithm: Algorithm) -> Key {
+        Key {
+            name:      "test.key".to_string(),
+            algorithm: algorithm,
+            secret:    b"0123456789abcdef".to_vec(),
+        }
+    }
+
+    // A query for example.com A
+    fn request() -> Vec<u8> {
+        let mut message = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        message.extend(wire_name("example.com"));
+        message.extend_from_slice(&[0, 1, 0, 1]);
+        return message;
+    }
+
+    fn mac_at(key: &Key, time: u64) -> Vec<u8> {
+        return key.mac(&digest(&[], &request(), &variables(key, time, 0, &[])));
+    }
+
+    fn verify(raw: &[u8], key: &Key) -> Result<(), u16> {
+        let packet = DnsPacket::get_packet_from_buffer(&mut PacketBuffer::from_bytes(raw)).unwrap();
+        return verify_request(raw, &packet, std::slice::from_ref(key))
+            .map(|session| assert!(session.is_some()))
+            .map_err(|rejection| rejection.error);
+    }
+
+    fn hex(bytes: &[u8]) -> String {
+        return bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    }
+
+    #[test]
+    fn known_answer() {
+        assert_eq!(hex(&mac_at(&key(Algorithm::HmacSha256), 1_700_000_000)),
+                   "98b8796eca004b225768f43d30b7d6c2e57287673e87a80b9d73e80e3460d337");
+        assert_eq!(hex(&mac_at(&key(Algorithm::HmacSha512), 1_700_000_000)),
+                   "168aced72cba4cb1c4b58a2e90663704a1279c98c7a595fa63ab6b353e4893eb\
+                    5df1cdb787c898fe108420c025e0941dff7e23da9ccb48c528d6115be51df3d4");
+    }
+
+    #[test]
+    fn signed_request() {
+        let key = key(Algorithm::HmacSha256);
+        let raw = Session::new(&key).sign(&request());
+        assert_eq!(verify(&raw, &key), Ok(()));
+
+        // The same signature checked against a different secret
+        let other = Key { secret: b"fedcba9876543210".to_vec(), ..key.clone() };
+        assert_eq!(verify(&raw, &other), Err(BADSIG));
+
+        // Nothing signed passes through
+        let packet = DnsPacket::get_packet_from_buffer(&mut PacketBuffer::from_bytes(&request())).unwrap();
+        assert!(matches!(verify_request(&request(), &packet, &[key]), Ok(None)));
+    }
+
+    #[test]
+    fn bad_mac() {
+        let key  = key(Algorithm::HmacSha256);
+        let time = now();
+        let mac  = mac_at(&key, time);
+
+        let mut altered = mac.clone();
+        altered[31] ^= 1;
+        assert_eq!(verify(&append_record(&request(), &key, time, &altered, 0, &[]), &key), Err(BADSIG));
+
+        // A prefix of the right MAC isn't accepted either
+        assert_eq!(verify(&append_record(&request(), &key, time, &mac[..16], 0, &[]), &key), Err(BADSIG));
+        assert_eq!(verify(&append_record(&request(), &key, time, &[], 0, &[]), &key), Err(BADSIG));
+
+        // The message changed after it was signed
+        let mut raw = append_record(&request(), &key, time, &mac, 0, &[]);
+        raw[3] ^= 0x10;
+        assert_eq!(verify(&raw, &key), Err(BADSIG));
+    }
+
+    #[test]
+    fn unknown_key() {
+        let key  = key(Algorithm::HmacSha256);
+        let time = now();
+        let raw  = append_record(&request(), &key, time, &mac_at(&key, time), 0, &[]);
+
+        let renamed = Key { name: "other.key".to_string(), ..key.clone() };
+        assert_eq!(verify(&raw, &renamed), Err(BADKEY));
+
+        // Same name, other algorithm
+        let sha512 = Key { algorithm: Algorithm::HmacSha512, ..key };
+        assert_eq!(verify(&raw, &sha512), Err(BADKEY));
+    }
+
+    #[test]
+    fn fudge_window() {
+        let key    = key(Algorithm::HmacSha256);
+        let signed = 1_700_000_000;
+        let raw    = append_record(&request(), &key, signed, &mac_at(&key, signed), 0, &[]);
+        let packet = DnsPacket::get_packet_from_buffer(&mut PacketBuffer::from_bytes(&raw)).unwrap();
+        let record = TsigRecord::parse(packet.additional_section.last().unwrap()).unwrap();
+
+        assert_eq!(record.fudge, FUDGE);
+        assert!(record.in_time(signed));
+        assert!(record.in_time(signed - 300));
+        assert!(record.in_time(signed + 300));
+        assert!(!record.in_time(signed - 301));
+        assert!(!record.in_time(signed + 301));
+
+        // Outside the window the signature is still checked first, then BADTIME
+        let time = now() - 301;
+        let raw  = append_record(&request(), &key, time, &mac_at(&key, time), 0, &[]);
+        assert_eq!(verify(&raw, &key), Err(BADTIME));
+
+        let mut altered = mac_at(&key, time);
+        altered[0] ^= 1;
+        assert_eq!(verify(&append_record(&request(), &key, time, &altered, 0, &[]), &key), Err(BADSIG));
+    }
+}
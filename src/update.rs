@@ -0,0 +1,388 @@
+use std::collections::HashSet;
+
+use crate::dns_cache::is_subdomain;
+use crate::dns_class::DnsClass;
+use crate::dns_query_type::QueryType;
+use crate::dns_record::DnsRecord;
+use crate::dns_result_code::ResultCode;
+use crate::zone::{serial_newer, Zone};
+
+pub const OPCODE_UPDATE: u8 = 5;
+
+const ANY: QueryType = QueryType::UNKNOWN(255);
+
+// Checks the prerequisites against the zone and applies the updates to a copy of it
+// (RFC 2136 3.2 to 3.4). Returns None when the updates didn't change anything, and
+// otherwise the new zone with its serial bumped.
+pub fn apply(zone: &Zone, prerequisites: &[DnsRecord], updates: &[DnsRecord]) -> Result<Option<Zone>, ResultCode> {
+    for record in prerequisites.iter().chain(updates) {
+        if !is_subdomain(&record.get_domain().to_lowercase(), &zone.origin) {
+            return Err(ResultCode::NOTZONE);
+        }
+    }
+
+    let mut records: Vec<DnsRecord> = zone.records().into_iter().cloned().collect();
+    check_prerequisites(&records, prerequisites)?;
+
+    for update in updates {
+        prescan(update)?;
+    }
+
+    let mut changed = false;
+    for update in updates {
+        changed |= apply_update(&mut records, &zone.origin, update);
+    }
+
+    if !changed {
+        return Ok(None);
+    }
+
+    // Unless the update brought a newer SOA of its own, the serial goes up by one
+    for record in records.iter_mut() {
+        if let DnsRecord::SOA { ref mut serial, .. } = *record {
+            if *serial == zone.serial() {
+                *serial = serial.wrapping_add(1);
+            }
+        }
+    }
+
+    return Zone::from_records(&zone.origin, records).map(Some).map_err(|err| {
+        warn!("Update would leave zone {}. invalid: {}", zone.origin, err);
+        ResultCode::SERVFAIL
+    });
+}
+
+// Class ANY asks for a name or RRset to exist, NONE for it not to, and the zone's own
+// class for an RRset to hold exactly the records given (RFC 2136 2.4)
+fn check_prerequisites(records: &[DnsRecord], prerequisites: &[DnsRecord]) -> Result<(), ResultCode> {
+    let mut required = Vec::new();
+
+    for prerequisite in prerequisites {
+        let name  = prerequisite.get_domain();
+        let qtype = record_type(prerequisite);
+
+        if prerequisite.get_ttl() != 0 {
+            return Err(ResultCode::FORMERR);
+        }
+
+        match prerequisite.get_class() {
+            DnsClass::ANY | DnsClass::NONE if !is_empty(prerequisite) => return Err(ResultCode::FORMERR),
+            DnsClass::ANY => {
+                if qtype == ANY && !name_in_use(records, name) {
+                    return Err(ResultCode::NXDOMAIN);
+                }
+
+                if qtype != ANY && rrset(records, name, qtype).is_empty() {
+                    return Err(ResultCode::NXRRSET);
+                }
+            },
+            DnsClass::NONE => {
+                if qtype == ANY && name_in_use(records, name) {
+                    return Err(ResultCode::YXDOMAIN);
+                }
+
+                if qtype != ANY && !rrset(records, name, qtype).is_empty() {
+                    return Err(ResultCode::YXRRSET);
+                }
+            },
+            DnsClass::IN => required.push(prerequisite),
+            _            => return Err(ResultCode::FORMERR),
+        }
+    }
+
+    // RRsets that have to match can be spread over several records, so they are
+    // compared once all of them are in
+    for prerequisite in &required {
+        let name  = prerequisite.get_domain();
+        let qtype = record_type(prerequisite);
+
+        let expected: HashSet<String> = required.iter()
+                                                .filter(|record| same_name(record, name) && record_type(record) == qtype)
+                                                .map(|record| data_key(record))
+                                                .collect();
+        let actual: HashSet<String>   = rrset(records, name, qtype).into_iter().map(data_key).collect();
+
+        if expected != actual {
+            return Err(ResultCode::NXRRSET);
+        }
+    }
+
+    return Ok(());
+}
+
+// Rejects malformed updates before anything is applied, so a bad one doesn't leave
+// the zone half changed (RFC 2136 3.4.1.3)
+fn prescan(update: &DnsRecord) -> Result<(), ResultCode> {
+    let qtype   = record_type(update);
+    let is_meta = qtype == QueryType::OPT || qtype.to_num() >= 251; // IXFR, AXFR, MAILB, MAILA and ANY
+
+    let valid = match update.get_class() {
+        DnsClass::IN   => !is_meta,
+        DnsClass::ANY  => update.get_ttl() == 0 && is_empty(update) && (!is_meta || qtype == ANY),
+        DnsClass::NONE => update.get_ttl() == 0 && !is_meta,
+        _              => false,
+    };
+
+    if !valid {
+        return Err(ResultCode::FORMERR);
+    }
+
+    return Ok(());
+}
+
+// Applies one update, returning whether it changed anything (RFC 2136 3.4.2)
+fn apply_update(records: &mut Vec<DnsRecord>, origin: &str, update: &DnsRecord) -> bool {
+    let name    = update.get_domain();
+    let qtype   = record_type(update);
+    let at_apex = name.eq_ignore_ascii_case(origin);
+    let before  = records.len();
+
+    match update.get_class() {
+        DnsClass::IN => return add(records, update, at_apex),
+        // The SOA and NS records at the apex can't be deleted as a whole
+        DnsClass::ANY => records.retain(|record| {
+            let record_qtype = record_type(record);
+            let protected    = at_apex && (record_qtype == QueryType::SOA || record_qtype == QueryType::NS);
+            !same_name(record, name) || (qtype != ANY && record_qtype != qtype) || protected
+        }),
+        DnsClass::NONE => {
+            // Neither can the SOA, nor the last NS record
+            if qtype == QueryType::SOA || (at_apex && qtype == QueryType::NS && rrset(records, name, qtype).len() <= 1) {
+                return false;
+            }
+
+            let key = data_key(update);
+            records.retain(|record| data_key(record) != key);
+        },
+        _ => (),
+    }
+
+    return records.len() != before;
+}
+
+fn add(records: &mut Vec<DnsRecord>, update: &DnsRecord, at_apex: bool) -> bool {
+    let name  = update.get_domain();
+    let qtype = record_type(update);
+
+    // The SOA is replaced, and only by one with a newer serial
+    if let DnsRecord::SOA { serial, .. } = *update {
+        let Some(current) = records.iter_mut().find(|record| record_type(record) == QueryType::SOA) else {
+            return false;
+        };
+
+        let newer = matches!(*current, DnsRecord::SOA { serial: current_serial, .. } if serial_newer(serial, current_serial));
+        if !at_apex || !newer {
+            return false;
+        }
+
+        *current = update.clone();
+        return true;
+    }
+
+    // A CNAME can't share its name with other data, an addition that would break that
+    // is ignored
+    let has_cname = !rrset(records, name, QueryType::CNAME).is_empty();
+    let has_other = records.iter().any(|record| same_name(record, name) && record_type(record) != QueryType::CNAME);
+    if (qtype == QueryType::CNAME && has_other) || (qtype != QueryType::CNAME && has_cname) {
+        return false;
+    }
+
+    // Adding a record that is already there only updates its TTL, and a new CNAME
+    // replaces the old one
+    let key = data_key(update);
+    if let Some(existing) = records.iter_mut().find(|record| data_key(record) == key) {
+        if existing.get_ttl() == update.get_ttl() {
+            return false;
+        }
+
+        existing.set_ttl(update.get_ttl());
+        return true;
+    }
+
+    if qtype == QueryType::CNAME {
+        records.retain(|record| !same_name(record, name) || record_type(record) != QueryType::CNAME);
+    }
+
+    records.push(update.clone());
+    return true;
+}
+
+fn rrset<'a>(records: &'a [DnsRecord], name: &str, qtype: QueryType) -> Vec<&'a DnsRecord> {
+    return records.iter()
+                  .filter(|record| same_name(record, name) && record_type(record) == qtype)
+                  .collect();
+}
+
+fn name_in_use(records: &[DnsRecord], name: &str) -> bool {
+    return records.iter().any(|record| same_name(record, name));
+}
+
+fn same_name(record: &DnsRecord, name: &str) -> bool {
+    return record.get_domain().eq_ignore_ascii_case(name);
+}
+
+// Records without RDATA come back from the wire as UNKNOWN, whatever their type
+fn record_type(record: &DnsRecord) -> QueryType {
+    return QueryType::from_num(record.get_qtype().to_num());
+}
+
+fn is_empty(record: &DnsRecord) -> bool {
+    return matches!(*record, DnsRecord::UNKNOWN { ref data, .. } if data.is_empty());
+}
+
+// Owner, type and RDATA, which is what makes two records the same regardless of TTL
+// and class
+fn data_key(record: &DnsRecord) -> String {
+    let text  = record.to_string();
+    let rdata = text.splitn(5, ' ').nth(4).unwrap_or("");
+    return format!("{} {} {}", record.get_domain().to_lowercase(), record_type(record), rdata);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    fn soa(serial: u32, class: DnsClass) -> DnsRecord {
+        let ttl = if class == DnsClass::IN { 300 } else { 0 };
+        return DnsRecord::SOA {
+            domain:  "example.com".to_string(),
+            mname:   "ns1.example.com".to_string(),
+            rname:   "hostmaster.example.com".to_string(),
+            serial:  serial,
+            refresh: 3600,
+            retry:   600,
+            expire:  86400,
+            minimum: 60,
+            class:   class,
+            ttl:     ttl,
+        };
+    }
+
+    fn ns(host: &str, class: DnsClass) -> DnsRecord {
+        let ttl = if class == DnsClass::IN { 300 } else { 0 };
+        return DnsRecord::NS { domain: "example.com".to_string(), host: host.to_string(), class: class, ttl: ttl };
+    }
+
+    fn a(name: &str, last: u8, class: DnsClass, ttl: u32) -> DnsRecord {
+        return DnsRecord::A { domain: name.to_string(), addr: Ipv4Addr::new(192, 0, 2, last), class: class, ttl: ttl };
+    }
+
+    // A record without RDATA, as prerequisites and deletions of whole RRsets come
+    fn empty(name: &str, qtype: QueryType, class: DnsClass) -> DnsRecord {
+        return DnsRecord::UNKNOWN { domain: name.to_string(), qtype: qtype.to_num(), data: Vec::new(), class: class, ttl: 0 };
+    }
+
+    fn zone() -> Zone {
+        let records = vec![
+            soa(1, DnsClass::IN),
+            ns("ns1.example.com", DnsClass::IN),
+            a("ns1.example.com", 53, DnsClass::IN, 300),
+            a("www.example.com", 1, DnsClass::IN, 300),
+            a("www.example.com", 2, DnsClass::IN, 300),
+        ];
+
+        return Zone::from_records("example.com", records).unwrap();
+    }
+
+    fn records(zone: &Zone) -> Vec<String> {
+        let mut records: Vec<String> = zone.records().iter().map(|record| record.to_string()).collect();
+        records.sort();
+        return records;
+    }
+
+    fn rcode(result: Result<Option<Zone>, ResultCode>) -> Option<u16> {
+        return result.err().map(ResultCode::to_num);
+    }
+
+    #[test]
+    fn name_in_use() {
+        let zone = zone();
+
+        assert_eq!(rcode(apply(&zone, &[empty("www.example.com", ANY, DnsClass::ANY)], &[])), None);
+        assert_eq!(rcode(apply(&zone, &[empty("new.example.com", ANY, DnsClass::ANY)], &[])), Some(ResultCode::NXDOMAIN.to_num()));
+        assert_eq!(rcode(apply(&zone, &[empty("www.example.com", ANY, DnsClass::NONE)], &[])), Some(ResultCode::YXDOMAIN.to_num()));
+        assert_eq!(rcode(apply(&zone, &[empty("new.example.com", ANY, DnsClass::NONE)], &[])), None);
+    }
+
+    #[test]
+    fn rrset_exists() {
+        let zone = zone();
+
+        assert_eq!(rcode(apply(&zone, &[empty("www.example.com", QueryType::A, DnsClass::ANY)], &[])), None);
+        assert_eq!(rcode(apply(&zone, &[empty("www.example.com", QueryType::TXT, DnsClass::ANY)], &[])), Some(ResultCode::NXRRSET.to_num()));
+        assert_eq!(rcode(apply(&zone, &[empty("www.example.com", QueryType::A, DnsClass::NONE)], &[])), Some(ResultCode::YXRRSET.to_num()));
+        assert_eq!(rcode(apply(&zone, &[empty("www.example.com", QueryType::TXT, DnsClass::NONE)], &[])), None);
+    }
+
+    #[test]
+    fn rrset_matches_exactly() {
+        let zone = zone();
+
+        let both = [a("www.example.com", 1, DnsClass::IN, 0), a("www.example.com", 2, DnsClass::IN, 0)];
+        assert_eq!(rcode(apply(&zone, &both, &[])), None);
+
+        let one = [a("www.example.com", 1, DnsClass::IN, 0)];
+        assert_eq!(rcode(apply(&zone, &one, &[])), Some(ResultCode::NXRRSET.to_num()));
+    }
+
+    #[test]
+    fn malformed_prerequisites_and_updates() {
+        let zone = zone();
+
+        let with_ttl = [a("www.example.com", 1, DnsClass::IN, 300)];
+        assert_eq!(rcode(apply(&zone, &with_ttl, &[])), Some(ResultCode::FORMERR.to_num()));
+
+        let outside = [a("www.example.org", 1, DnsClass::IN, 300)];
+        assert_eq!(rcode(apply(&zone, &[], &outside)), Some(ResultCode::NOTZONE.to_num()));
+
+        // The bad second update keeps the first from being applied
+        let updates = [a("new.example.com", 9, DnsClass::IN, 300), a("www.example.com", 1, DnsClass::ANY, 0)];
+        assert_eq!(rcode(apply(&zone, &[], &updates)), Some(ResultCode::FORMERR.to_num()));
+    }
+
+    #[test]
+    fn additions_bump_the_serial() {
+        let zone    = zone();
+        let updated = apply(&zone, &[], &[a("new.example.com", 9, DnsClass::IN, 300)]).unwrap().unwrap();
+
+        assert_eq!(updated.serial(), 2);
+        assert!(records(&updated).contains(&"new.example.com. 300 IN A 192.0.2.9".to_string()));
+
+        // Adding what is already there changes nothing
+        assert!(apply(&zone, &[], &[a("www.example.com", 1, DnsClass::IN, 300)]).unwrap().is_none());
+    }
+
+    #[test]
+    fn apex_soa_and_ns_are_protected() {
+        let zone = zone();
+
+        // Deleting every RRset at the apex leaves the SOA and NS records
+        let updated = apply(&zone, &[], &[empty("example.com", ANY, DnsClass::ANY)]).unwrap();
+        assert!(updated.is_none());
+
+        let updated = apply(&zone, &[], &[empty("example.com", QueryType::NS, DnsClass::ANY)]).unwrap();
+        assert!(updated.is_none());
+
+        // Neither the SOA nor the last NS record can be deleted one by one
+        assert!(apply(&zone, &[], &[soa(1, DnsClass::NONE)]).unwrap().is_none());
+        assert!(apply(&zone, &[], &[ns("ns1.example.com", DnsClass::NONE)]).unwrap().is_none());
+
+        // With a second NS record the first one can go
+        let updates = [ns("ns2.example.com", DnsClass::IN), ns("ns1.example.com", DnsClass::NONE)];
+        let updated = apply(&zone, &[], &updates).unwrap().unwrap();
+        assert!(records(&updated).contains(&"example.com. 300 IN NS ns2.example.com.".to_string()));
+        assert!(!records(&updated).contains(&"example.com. 300 IN NS ns1.example.com.".to_string()));
+    }
+
+    #[test]
+    fn soa_is_only_replaced_by_a_newer_one() {
+        let zone = zone();
+
+        assert!(apply(&zone, &[], &[soa(1, DnsClass::IN)]).unwrap().is_none());
+
+        let updated = apply(&zone, &[], &[soa(10, DnsClass::IN)]).unwrap().unwrap();
+        assert_eq!(updated.serial(), 10);
+    }
+}
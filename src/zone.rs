@@ -0,0 +1,685 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, RwLock};
+
+use crate::dns_class::DnsClass;
+use crate::dns_packet::DnsPacket;
+use crate::dns_query_type::QueryType;
+use crate::dns_cache::is_subdomain;
+use crate::dns_record::{fqdn, DnsRecord};
+use crate::dns_result_code::ResultCode;
+use crate::journal::{Diff, Journal};
+use crate::master_file;
+use crate::resolver::MAX_CNAME_CHAIN;
+
+// What a zone holds for a name, before following any CNAME out of it
+enum Lookup {
+    Answer(Vec<DnsRecord>),
+    Cname(DnsRecord, String),
+    Referral(Vec<DnsRecord>), // NS records of a delegation between the apex and the name
+    NoData,
+    NxDomain,
+}
+
+// A zone we are authoritative for, loaded from a master file
+#[derive(Clone)]
+pub struct Zone {
+    pub origin: String,
+    nodes:      BTreeMap<String, Vec<DnsRecord>>, // Keyed by tree_key() of the owner
+}
+
+impl Zone {
+    pub fn load(origin: &str, path: &Path) -> Result<Self, String> {
+        let records = master_file::load(path, origin)?;
+        return Self::from_records(origin, records).map_err(|err| format!("{}: {}", path.display(), err));
+    }
+
+    // Builds the zone from its records in any order, checking they make a valid zone
+    pub fn from_records(origin: &str, records: Vec<DnsRecord>) -> Result<Self, String> {
+        let mut zone = Self {
+            origin: origin.to_lowercase(),
+            nodes:  BTreeMap::new(),
+        };
+
+        for record in records {
+            let owner = record.get_domain().to_lowercase();
+            if !is_subdomain(&owner, &zone.origin) {
+                return Err(format!("{} is outside of zone {}", fqdn(&owner), fqdn(&zone.origin)));
+            }
+
+            if record.get_class() != DnsClass::IN {
+                return Err(format!("{} is not in class IN", record));
+            }
+
+            // A record given twice is there once, so diffs can count on deleting it once
+            let node = zone.nodes.entry(tree_key(&owner)).or_default();
+            if !node.iter().any(|existing| existing.to_string() == record.to_string()) {
+                node.push(record);
+            }
+        }
+
+        let soa_count = zone.nodes.values().flatten().filter(|record| record.get_qtype() == QueryType::SOA).count();
+        if soa_count != 1 || zone.records_at(&zone.origin, QueryType::SOA).is_empty() {
+            return Err(format!("zone {} needs exactly one SOA record, at its apex", fqdn(&zone.origin)));
+        }
+
+        if zone.records_at(&zone.origin, QueryType::NS).is_empty() {
+            return Err(format!("zone {} has no NS records at its apex", fqdn(&zone.origin)));
+        }
+
+        for records in zone.nodes.values() {
+            let has_cname = records.iter().any(|record| record.get_qtype() == QueryType::CNAME);
+            if has_cname && records.len() > 1 {
+                return Err(format!("{} has a CNAME and other records", fqdn(records[0].get_domain())));
+            }
+        }
+
+        return Ok(zone);
+    }
+
+    pub fn soa(&self) -> &DnsRecord {
+        // load() made sure there is one
+        return self.nodes[&tree_key(&self.origin)].iter()
+                                                  .find(|record| record.get_qtype() == QueryType::SOA)
+                                                  .unwrap();
+    }
+
+    // The zone after applying the changes in order, each of which has to start from
+    // the serial the one before it left off at
+    pub fn apply(&self, diffs: &[Diff]) -> Result<Self, String> {
+        let mut records: Vec<DnsRecord> = self.records().into_iter().cloned().collect();
+        let mut serial                  = self.serial();
+
+        for diff in diffs {
+            if diff.old_serial() != serial {
+                return Err(format!("change from serial {} doesn't apply to serial {}", diff.old_serial(), serial));
+            }
+
+            let deleted: HashSet<String> = diff.deleted.iter().map(|record| record.to_string()).collect();
+            let before                   = records.len();
+            records.retain(|record| !deleted.contains(&record.to_string()));
+
+            if before - records.len() != deleted.len() {
+                return Err(format!("change to serial {} deletes records the zone doesn't have", diff.new_serial()));
+            }
+
+            records.extend(diff.added.iter().cloned());
+            serial = diff.new_serial();
+        }
+
+        return Self::from_records(&self.origin, records);
+    }
+
+    // This zone with the records of `changes` deleted and added, under the serial after
+    // its own. Meant for carrying changes over to another version of the zone, so
+    // records that are already gone or already there are left as they are.
+    pub fn merge(&self, changes: &Diff) -> Result<Self, String> {
+        let is_soa = |record: &&DnsRecord| record.get_qtype() == QueryType::SOA;
+
+        let deleted: HashSet<String> = changes.deleted.iter().filter(|record| !is_soa(record)).map(|record| record.to_string()).collect();
+        let mut records: Vec<DnsRecord> = self.records()
+                                              .into_iter()
+                                              .filter(|record| !deleted.contains(&record.to_string()))
+                                              .cloned()
+                                              .collect();
+
+        let present: HashSet<String> = records.iter().map(|record| record.to_string()).collect();
+        records.extend(changes.added
+                              .iter()
+                              .filter(|record| !is_soa(record) && !present.contains(&record.to_string()))
+                              .cloned());
+
+        // records() puts the SOA first
+        if let DnsRecord::SOA { ref mut serial, .. } = records[0] {
+            *serial = serial.wrapping_add(1);
+        }
+
+        return Self::from_records(&self.origin, records);
+    }
+
+    pub fn serial(&self) -> u32 {
+        match *self.soa() {
+            DnsRecord::SOA { serial, .. } => serial,
+            _                             => unreachable!(),
+        }
+    }
+
+    // Every record with the SOA first, the order a zone transfer sends them in
+    pub fn records(&self) -> Vec<&DnsRecord> {
+        let mut records = vec![self.soa()];
+        records.extend(self.nodes.values()
+                                 .flatten()
+                                 .filter(|record| record.get_qtype() != QueryType::SOA));
+        return records;
+    }
+
+    pub fn record_count(&self) -> usize {
+        return self.nodes.values().map(Vec::len).sum();
+    }
+
+    // The SOA for the authority section of negative answers, whose TTL is also how
+    // long the answer may be cached (RFC 2308 3)
+    fn negative_soa(&self) -> DnsRecord {
+        let mut soa = self.soa().clone();
+        if let DnsRecord::SOA { minimum, ttl, .. } = soa {
+            soa.set_ttl(ttl.min(minimum));
+        }
+
+        return soa;
+    }
+
+    fn records_at(&self, name: &str, qtype: QueryType) -> Vec<DnsRecord> {
+        return self.nodes.get(&tree_key(name))
+                         .into_iter()
+                         .flatten()
+                         .filter(|record| qtype == QueryType::UNKNOWN(255) || record.get_qtype() == qtype)
+                         .cloned()
+                         .collect();
+    }
+
+    // Whether the name owns records or has descendants that do, the latter making it
+    // an empty non-terminal
+    fn name_exists(&self, name: &str) -> bool {
+        let key = tree_key(name);
+        return self.nodes.range(key.clone()..)
+                         .next()
+                         .is_some_and(|(next, _)| next.starts_with(&key));
+    }
+
+    // RFC 1034 4.3.2 step 3, for a name at or below the origin
+    fn lookup(&self, qname: &str, qtype: QueryType) -> Lookup {
+        let qname  = qname.to_lowercase();
+        let labels = qname.split('.').filter(|label| !label.is_empty()).collect::<Vec<_>>();
+        let depth  = labels.len() - self.origin.split('.').filter(|label| !label.is_empty()).count();
+
+        // Walk down from just below the apex, a delegation hides everything under it
+        for start in (0..depth).rev() {
+            let name = labels[start..].join(".");
+            let ns   = self.records_at(&name, QueryType::NS);
+            if !ns.is_empty() {
+                return Lookup::Referral(ns);
+            }
+        }
+
+        // A name that exists only as an empty non-terminal never matches a wildcard
+        let records = match self.records_at(&qname, QueryType::UNKNOWN(255)) {
+            records if !records.is_empty() => records,
+            _ if self.name_exists(&qname)  => return Lookup::NoData,
+            _                              => match self.synthesize(&qname, &labels, depth) {
+                Some(records) => records,
+                None          => return Lookup::NxDomain,
+            },
+        };
+
+        if let Some(DnsRecord::CNAME { host, .. }) = records.first() {
+            if qtype != QueryType::CNAME && qtype != QueryType::UNKNOWN(255) {
+                return Lookup::Cname(records[0].clone(), host.clone());
+            }
+        }
+
+        let answers: Vec<DnsRecord> = records.into_iter()
+                                             .filter(|record| qtype == QueryType::UNKNOWN(255) || record.get_qtype() == qtype)
+                                             .collect();
+
+        match answers.is_empty() {
+            true  => Lookup::NoData,
+            false => Lookup::Answer(answers),
+        }
+    }
+
+    // Records of the wildcard matching a name that doesn't exist, with the name as
+    // their owner. Only the wildcard right below the closest encloser, the nearest
+    // ancestor that exists, can match (RFC 4592 3.3.1).
+    fn synthesize(&self, qname: &str, labels: &[&str], depth: usize) -> Option<Vec<DnsRecord>> {
+        // labels[depth..] is the apex, which always exists
+        let encloser = (1..=depth).map(|start| labels[start..].join("."))
+                                  .find(|name| self.name_exists(name))?;
+
+        let source = match encloser.as_str() {
+            ""       => "*".to_string(),
+            encloser => format!("*.{}", encloser),
+        };
+
+        let mut records = self.records_at(&source, QueryType::UNKNOWN(255));
+        if records.is_empty() {
+            return None;
+        }
+
+        for record in &mut records {
+            record.set_domain(qname);
+        }
+
+        return Some(records);
+    }
+}
+
+// Every zone we serve authoritatively, keyed by origin. Zones are swapped out
+// whole, so a query sees either the old or the new copy and never a mix.
+pub struct ZoneStore {
+    zones: RwLock<HashMap<String, ZoneEntry>>,
+}
+
+struct ZoneEntry {
+    zone:    Option<Arc<Zone>>, // None for a secondary without data yet
+    journal: Journal,
+    refresh: Option<Sender<()>>, // Wakes up the thread keeping a secondary in sync
+}
+
+impl ZoneStore {
+    pub fn new() -> Self {
+        Self {
+            zones: RwLock::new(HashMap::new()),
+        }
+    }
+
+    // Adds a zone we serve, or a secondary zone we're still waiting to transfer
+    pub fn insert(&self, origin: &str, zone: Option<Zone>, journal: Journal) {
+        let entry = ZoneEntry {
+            zone:    zone.map(Arc::new),
+            journal: journal,
+            refresh: None,
+        };
+
+        self.zones.write().unwrap().insert(origin.to_lowercase(), entry);
+    }
+
+    // Swaps in a new version of the zone, journaling what changed since the old one
+    pub fn commit(&self, zone: Zone) {
+        let mut zones = self.zones.write().unwrap();
+        let Some(entry) = zones.get_mut(&zone.origin) else {
+            return;
+        };
+
+        match entry.zone {
+            Some(ref current) => entry.journal.append(Diff::between(current, &zone)),
+            None              => entry.journal.reset(),
+        }
+
+        entry.zone = Some(Arc::new(zone));
+    }
+
+    // Runs `change` on the current zone and commits what it returns, all under the
+    // lock so concurrent changes can't undo each other
+    pub fn update(&self, origin: &str, change: impl FnOnce(&Zone) -> Result<Option<Zone>, ResultCode>) -> Result<Option<Arc<Zone>>, ResultCode> {
+        let mut zones = self.zones.write().unwrap();
+        let Some(entry) = zones.get_mut(origin) else {
+            return Err(ResultCode::NOTAUTH);
+        };
+
+        let Some(ref current) = entry.zone else {
+            return Err(ResultCode::SERVFAIL);
+        };
+
+        let Some(zone) = change(current)? else {
+            return Ok(None);
+        };
+
+        entry.journal.append(Diff::between(current, &zone));
+
+        let zone   = Arc::new(zone);
+        entry.zone = Some(Arc::clone(&zone));
+
+        return Ok(Some(zone));
+    }
+
+    // Swaps in a new version of a primary zone's master file, `previous` being the
+    // version before it. What dynamic updates changed on top of that is carried over
+    // under the serial after the file's. The file's change and the updates are
+    // journaled as two steps, so the journal leads from the serial on disk to the one
+    // we serve and a restart replays the updates again.
+    pub fn reload(&self, previous: &Zone, loaded: &Zone) -> Result<Arc<Zone>, String> {
+        let mut zones = self.zones.write().unwrap();
+        let Some(entry) = zones.get_mut(&loaded.origin) else {
+            return Err("the zone isn't served".to_string());
+        };
+
+        let Some(ref current) = entry.zone else {
+            return Err("the zone has no data".to_string());
+        };
+
+        if !serial_newer(loaded.serial(), current.serial()) {
+            return Err(format!("serial {} isn't newer than {}", loaded.serial(), current.serial()));
+        }
+
+        let updates = Diff::between(previous, current);
+        let zone    = match updates.only_soa() {
+            true  => loaded.clone(),
+            false => loaded.merge(&updates).map_err(|err| {
+                format!("the dynamic updates since serial {} don't fit it: {}", previous.serial(), err)
+            })?,
+        };
+
+        entry.journal.append(Diff::between(current, loaded));
+        if zone.serial() != loaded.serial() {
+            entry.journal.append(Diff::between(loaded, &zone));
+        }
+
+        let zone   = Arc::new(zone);
+        entry.zone = Some(Arc::clone(&zone));
+
+        return Ok(zone);
+    }
+
+    // Stops answering from a secondary zone whose data went stale, until it is
+    // transferred again
+    pub fn expire(&self, origin: &str) {
+        if let Some(entry) = self.zones.write().unwrap().get_mut(origin) {
+            entry.zone = None;
+        }
+    }
+
+    // Receives a message whenever someone asks for the secondary zone to be refreshed
+    // ahead of its timers
+    pub fn refresh_requests(&self, origin: &str) -> Receiver<()> {
+        let (sender, receiver) = mpsc::channel();
+        if let Some(entry) = self.zones.write().unwrap().get_mut(origin) {
+            entry.refresh = Some(sender);
+        }
+
+        return receiver;
+    }
+
+    // Asks the secondary zone to refresh now, false if it isn't one of ours
+    pub fn request_refresh(&self, origin: &str) -> bool {
+        return self.zones.read()
+                         .unwrap()
+                         .get(&origin.to_lowercase())
+                         .and_then(|entry| entry.refresh.as_ref())
+                         .is_some_and(|sender| sender.send(()).is_ok());
+    }
+
+    // The zone with exactly this origin
+    pub fn get(&self, origin: &str) -> Option<Arc<Zone>> {
+        return self.zones.read()
+                         .unwrap()
+                         .get(&origin.to_lowercase())
+                         .and_then(|entry| entry.zone.clone());
+    }
+
+    // The zone and, if the journal reaches back to `serial`, the changes since then
+    pub fn changes_since(&self, origin: &str, serial: u32) -> Option<(Arc<Zone>, Option<Vec<Diff>>)> {
+        let zones = self.zones.read().unwrap();
+        let entry = zones.get(&origin.to_lowercase())?;
+        let zone  = entry.zone.clone()?;
+        let diffs = entry.journal.diffs_since(serial, zone.serial());
+
+        return Some((zone, diffs));
+    }
+
+    // The closest enclosing zone of the name if we have one, which may not be loaded
+    fn find(&self, qname: &str) -> Option<Option<Arc<Zone>>> {
+        let qname = qname.to_lowercase();
+        return self.zones.read()
+                         .unwrap()
+                         .iter()
+                         .filter(|(origin, _)| is_subdomain(&qname, origin))
+                         .max_by_key(|(origin, _)| origin.len())
+                         .map(|(_, entry)| entry.zone.clone());
+    }
+
+    // The authoritative response for the question, or None when it's outside every zone
+    pub fn answer(&self, qname: &str, qtype: QueryType) -> Option<DnsPacket> {
+        let mut result = DnsPacket::new();
+        let mut zone   = match self.find(qname)? {
+            Some(zone) => zone,
+            None       => {
+                result.header.response_code = ResultCode::SERVFAIL;
+                return Some(result);
+            }
+        };
+
+        let mut qname                      = qname.to_string();
+        result.header.authoritative_answer = true;
+
+        // CNAMEs are followed for as long as they lead into a zone of ours
+        for _ in 0..MAX_CNAME_CHAIN {
+            match zone.lookup(&qname, qtype) {
+                Lookup::Answer(records) => {
+                    for record in &records {
+                        if let DnsRecord::NS { host, .. } | DnsRecord::MX { host, .. } | DnsRecord::SRV { host, .. } = record {
+                            result.additional_section.extend(self.addresses(host));
+                        }
+                    }
+
+                    result.answer_section.extend(records);
+                    return Some(result);
+                },
+                Lookup::Cname(record, target) => {
+                    result.answer_section.push(record);
+                    match self.find(&target).flatten() {
+                        Some(next) => {
+                            zone  = next;
+                            qname = target;
+                        },
+                        None => return Some(result),
+                    }
+                },
+                Lookup::Referral(ns) => {
+                    // The delegated zone's servers are the authority for the rest
+                    result.header.authoritative_answer = !result.answer_section.is_empty();
+                    for record in &ns {
+                        if let DnsRecord::NS { host, .. } = record {
+                            result.additional_section.extend(self.addresses(host));
+                        }
+                    }
+
+                    result.authority_section.extend(ns);
+                    return Some(result);
+                },
+                Lookup::NoData => {
+                    result.authority_section.push(zone.negative_soa());
+                    return Some(result);
+                },
+                Lookup::NxDomain => {
+                    result.header.response_code = ResultCode::NXDOMAIN;
+                    result.authority_section.push(zone.negative_soa());
+                    return Some(result);
+                },
+            }
+        }
+
+        return Some(result);
+    }
+
+    // A and AAAA records we hold for the host, glue below a delegation included
+    fn addresses(&self, host: &str) -> Vec<DnsRecord> {
+        let Some(zone) = self.find(host).flatten() else {
+            return Vec::new();
+        };
+
+        let mut addresses = zone.records_at(host, QueryType::A);
+        addresses.extend(zone.records_at(host, QueryType::AAAA));
+        return addresses;
+    }
+}
+
+// Labels in reverse order, each followed by a dot, so "www.example.com" becomes
+// "com.example.www." and everything below a name sorts right after it
+fn tree_key(name: &str) -> String {
+    return name.to_lowercase()
+               .split('.')
+               .rev()
+               .filter(|label| !label.is_empty())
+               .map(|label| format!("{}.", label))
+               .collect();
+}
+
+// Whether serial `a` is newer than `b`, in sequence space arithmetic (RFC 1982)
+pub fn serial_newer(a: u32, b: u32) -> bool {
+    return a != b && (a.wrapping_sub(b) as i32) > 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::update;
+
+    const ZONE: &str = "$TTL 300\n\
+                        @       SOA   ns1 hostmaster 1 3600 600 86400 60\n\
+                        @       NS    ns1\n\
+                        ns1     A     192.0.2.53\n\
+                        *       A     192.0.2.1\n\
+                        *       MX    10 mail\n\
+                        host    A     192.0.2.2\n\
+                        a.b.c   A     192.0.2.3\n\
+                        *.alias CNAME host\n\
+                        sub     NS    ns.sub\n\
+                        ns.sub  A     192.0.2.54\n\
+                        *.sub   A     192.0.2.9\n";
+
+    // Loads the zone from a master file of its own, the way the server does
+    fn load(test: &str, text: &str) -> Zone {
+        let path = std::env::temp_dir().join(format!("zone_{}_{}", test, std::process::id()));
+        fs::write(&path, text).unwrap();
+
+        let zone = Zone::load("example.com", &path);
+        fs::remove_file(&path).unwrap();
+
+        return zone.unwrap();
+    }
+
+    // A small zone at the serial with the records added
+    fn version(test: &str, serial: u32, records: &str) -> Zone {
+        return load(test, &format!("$TTL 300\n@ SOA ns1 hostmaster {} 3600 600 86400 60\n@ NS ns1\n{}", serial, records));
+    }
+
+    fn records(zone: &Zone) -> Vec<String> {
+        let mut records: Vec<String> = zone.records().iter().map(|record| record.to_string()).collect();
+        records.sort();
+        return records;
+    }
+
+    fn store(test: &str) -> ZoneStore {
+        let zones = ZoneStore::new();
+        zones.insert("example.com", Some(load(test, ZONE)), Journal::in_memory());
+        return zones;
+    }
+
+    // Response code, answers and authority records of the response
+    fn answer(zones: &ZoneStore, qname: &str, qtype: QueryType) -> (u16, Vec<String>, Vec<String>) {
+        let result = zones.answer(qname, qtype).unwrap();
+        return (result.header.response_code.to_num(),
+                result.answer_section.iter().map(|record| record.to_string()).collect(),
+                result.authority_section.iter().map(|record| record.to_string()).collect());
+    }
+
+    const SOA: &str = "example.com. 60 IN SOA ns1.example.com. hostmaster.example.com. 1 3600 600 86400 60";
+
+    #[test]
+    fn wildcard_matches_a_name_that_doesnt_exist() {
+        let zones = store("wildcard");
+
+        assert_eq!(answer(&zones, "nothing.example.com", QueryType::A),
+                   (0, vec!["nothing.example.com. 300 IN A 192.0.2.1".to_string()], vec![]));
+        assert_eq!(answer(&zones, "deeper.nothing.example.com", QueryType::MX),
+                   (0, vec!["deeper.nothing.example.com. 300 IN MX 10 mail.example.com.".to_string()], vec![]));
+
+        // The wildcard has no record of that type
+        assert_eq!(answer(&zones, "nothing.example.com", QueryType::AAAA), (0, vec![], vec![SOA.to_string()]));
+
+        // Asked for by its own name, the wildcard is an ordinary node
+        assert_eq!(answer(&zones, "*.example.com", QueryType::A).1, ["*.example.com. 300 IN A 192.0.2.1"]);
+    }
+
+    #[test]
+    fn names_below_an_existing_node_get_no_wildcard() {
+        let zones = store("existing");
+
+        // host exists, so it is the closest encloser and only *.host could match
+        assert_eq!(answer(&zones, "below.host.example.com", QueryType::A), (3, vec![], vec![SOA.to_string()]));
+
+        // An existing name without the type is NODATA, not the wildcard's records
+        assert_eq!(answer(&zones, "host.example.com", QueryType::MX), (0, vec![], vec![SOA.to_string()]));
+    }
+
+    #[test]
+    fn empty_non_terminals_are_nodata() {
+        let zones = store("ent");
+
+        assert_eq!(answer(&zones, "b.c.example.com", QueryType::A), (0, vec![], vec![SOA.to_string()]));
+        assert_eq!(answer(&zones, "c.example.com", QueryType::A), (0, vec![], vec![SOA.to_string()]));
+
+        // The empty non-terminal is the closest encloser and there is no *.c
+        assert_eq!(answer(&zones, "x.c.example.com", QueryType::A), (3, vec![], vec![SOA.to_string()]));
+    }
+
+    #[test]
+    fn wildcard_cname_is_followed() {
+        let zones = store("cname");
+
+        assert_eq!(answer(&zones, "www.alias.example.com", QueryType::A), (0, vec![
+            "www.alias.example.com. 300 IN CNAME host.example.com.".to_string(),
+            "host.example.com. 300 IN A 192.0.2.2".to_string(),
+        ], vec![]));
+
+        assert_eq!(answer(&zones, "www.alias.example.com", QueryType::CNAME).1, ["www.alias.example.com. 300 IN CNAME host.example.com."]);
+    }
+
+    #[test]
+    fn wildcard_below_a_delegation_is_not_used() {
+        let zones = store("delegation");
+
+        for qname in ["sub.example.com", "anything.sub.example.com", "*.sub.example.com"] {
+            let result = zones.answer(qname, QueryType::A).unwrap();
+            assert!(!result.header.authoritative_answer);
+            assert!(result.answer_section.is_empty());
+            assert_eq!(result.authority_section[0].to_string(), "sub.example.com. 300 IN NS ns.sub.example.com.");
+            assert_eq!(result.additional_section[0].to_string(), "ns.sub.example.com. 300 IN A 192.0.2.54");
+        }
+    }
+
+    #[test]
+    fn repeated_records_are_deleted_once() {
+        let zone = version("repeated", 1, "www A 192.0.2.1\nwww A 192.0.2.1\nwww A 192.0.2.2\n");
+        assert_eq!(zone.record_count(), 4);
+
+        // A diff may name a deleted record more than once too
+        let updated  = version("repeated_2", 2, "www A 192.0.2.2\n");
+        let mut diff = Diff::between(&zone, &updated);
+        diff.deleted.push(diff.deleted[1].clone());
+        assert_eq!(records(&zone.apply(&[diff]).unwrap()), records(&updated));
+
+        // Deleting what isn't there still fails
+        let mut diff = Diff::between(&zone, &updated);
+        diff.deleted.push(version("repeated_3", 1, "ftp A 192.0.2.3\n").records()[2].clone());
+        assert!(zone.apply(&[diff]).is_err());
+    }
+
+    #[test]
+    fn updates_survive_a_reload_and_a_restart() {
+        let dir = std::env::temp_dir().join(format!("zone_journal_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("example.com.jnl");
+
+        let file        = version("restart", 1, "www A 192.0.2.1\n");
+        let zones       = ZoneStore::new();
+        let mut journal = Journal::open(path.clone(), "example.com");
+        zones.insert("example.com", Some(journal.replay(file.clone())), journal);
+
+        let dynamic = DnsRecord::A { domain: "dyn.example.com".to_string(), addr: "192.0.2.7".parse().unwrap(), class: DnsClass::IN, ttl: 300 };
+        zones.update("example.com", |zone| update::apply(zone, &[], &[dynamic])).unwrap();
+
+        // The file changes on its own, the update is carried over under the serial after it
+        let edited = version("restart_2", 10, "www A 192.0.2.2\n");
+        let served = zones.reload(&file, &edited).unwrap();
+        assert_eq!(served.serial(), 11);
+        assert_eq!(records(&served), records(&version("restart_3", 11, "www A 192.0.2.2\ndyn A 192.0.2.7\n")));
+
+        // Secondaries at any serial so far can follow the journal
+        let (_, diffs) = zones.changes_since("example.com", 1).unwrap();
+        let serials: Vec<(u32, u32)> = diffs.unwrap().iter().map(|diff| (diff.old_serial(), diff.new_serial())).collect();
+        assert_eq!(serials, [(1, 2), (2, 10), (10, 11)]);
+
+        // After a restart the file is at 10 and the journal takes it to what was served
+        let mut journal = Journal::open(path, "example.com");
+        let restarted   = journal.replay(edited);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(records(&restarted), records(&served));
+
+        // A reload has to move the serial past what is served, not just past the file
+        assert!(zones.reload(&file, &version("restart_4", 11, "")).is_err());
+    }
+}
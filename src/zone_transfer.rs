@@ -0,0 +1,487 @@
+use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::dns_class::DnsClass;
+use crate::dns_packet::DnsPacket;
+use crate::dns_query_type::QueryType;
+use crate::dns_question::DnsQuestion;
+use crate::dns_record::DnsRecord;
+use crate::dns_result_code::ResultCode;
+use crate::dns_tcp;
+use crate::journal::{soa_serial, Diff};
+use crate::notify;
+use crate::packet_buffer::{PacketBuffer, MAX_PACKET_SIZE};
+use crate::resolver::{lookup, ResolverConfig};
+use crate::tsig::{Key, Session};
+use crate::zone::{serial_newer, Zone, ZoneStore};
+
+pub const IXFR: QueryType = QueryType::UNKNOWN(251);
+pub const AXFR: QueryType = QueryType::UNKNOWN(252);
+
+// Records per transfer message are capped well below the 64KB TCP limit, like other
+// servers do, so one large message doesn't hold up the stream
+const MESSAGE_SIZE: usize = 16384;
+
+// How long a secondary waits before retrying when it has no SOA to take timers from
+const INITIAL_RETRY: Duration = Duration::from_secs(30);
+
+// What an IXFR brought back
+pub enum Transfer {
+    UpToDate,
+    Incremental(Vec<Diff>),
+    Full(Vec<DnsRecord>), // The primary sent the whole zone instead
+}
+
+// Reads the records of a transfer response one at a time, across however many
+// messages the primary split it into
+struct TransferReader {
+    stream:   TcpStream,
+    request:  DnsPacket,
+    session:  Option<Session>, // When the transfer is signed with a TSIG key
+    messages: usize,
+    records:  std::vec::IntoIter<DnsRecord>,
+}
+
+impl TransferReader {
+    fn start(primary: SocketAddr, request: DnsPacket, key: Option<&Key>, timeout: Duration) -> Result<Self, String> {
+        let mut stream = TcpStream::connect_timeout(&primary, timeout).map_err(|err| format!("could not connect: {}", err))?;
+        let _          = stream.set_read_timeout(Some(timeout));
+
+        let mut request_buffer = PacketBuffer::with_limit(MAX_PACKET_SIZE);
+        request.clone().write_packet_to_buffer(&mut request_buffer).map_err(|err| err.to_string())?;
+
+        let mut session = key.map(Session::new);
+        let message     = match session {
+            Some(ref mut session) => session.sign(request_buffer.get_data()),
+            None                  => request_buffer.get_data().to_vec(),
+        };
+
+        dns_tcp::write_message(&mut stream, &message).map_err(|err| err.to_string())?;
+
+        return Ok(Self {
+            stream:   stream,
+            request:  request,
+            session:  session,
+            messages: 0,
+            records:  Vec::new().into_iter(),
+        });
+    }
+
+    fn next(&mut self) -> Result<DnsRecord, String> {
+        loop {
+            if let Some(record) = self.records.next() {
+                return Ok(record);
+            }
+
+            let mut buffer = match dns_tcp::read_message(&mut self.stream) {
+                Ok(Some(buffer)) => buffer,
+                Ok(None)         => return Err("connection closed before the closing SOA".to_string()),
+                Err(err)         => return Err(err.to_string()),
+            };
+
+            let response = DnsPacket::get_packet_from_buffer(&mut buffer).map_err(|err| err.to_string())?;
+
+            // Only the first message has to carry the question
+            let matches = match self.messages {
+                0 => response.is_response_to(&self.request),
+                _ => response.header.query_response
+                     && response.header.packet_identifier == self.request.header.packet_identifier,
+            };
+
+            if !matches {
+                return Err("reply doesn't match the request".to_string());
+            }
+
+            if !matches!(response.header.response_code, ResultCode::NOERROR) {
+                return Err(format!("primary answered {:?}", response.header.response_code));
+            }
+
+            if let Some(ref mut session) = self.session {
+                session.verify(buffer.get_data(), &response)?;
+            }
+
+            self.messages += 1;
+            self.records   = response.answer_section.into_iter();
+        }
+    }
+
+    fn next_soa(&mut self) -> Result<DnsRecord, String> {
+        let record = self.next()?;
+        if record.get_qtype() != QueryType::SOA {
+            return Err("transfer doesn't start with an SOA".to_string());
+        }
+
+        return Ok(record);
+    }
+
+    // The transfer is only trusted once its last message was signed
+    fn finish(&self) -> Result<(), String> {
+        if self.session.as_ref().is_some_and(|session| !session.is_complete()) {
+            return Err("last message of the transfer isn't signed".to_string());
+        }
+
+        return Ok(());
+    }
+}
+
+// Streams the zone to the client in as many messages as it takes, starting and ending
+// with the SOA (RFC 5936 2.2)
+pub fn send_axfr(stream: &mut TcpStream, request: &DnsPacket, zone: &Zone, session: Option<&mut Session>) -> io::Result<()> {
+    let mut records = zone.records();
+    records.push(zone.soa());
+    send_records(stream, request, &records, session)?;
+
+    info!("Sent zone {}. serial {} with {} records over AXFR", zone.origin, zone.serial(), records.len() - 1);
+    return Ok(());
+}
+
+// Sends the changes from the client's serial up to ours, framed by our SOA (RFC 1995
+// 4). Without `diffs`, because the journal doesn't go back that far, the whole zone
+// goes out like for AXFR. A client that is up to date gets just the SOA.
+pub fn send_ixfr(stream: &mut TcpStream, request: &DnsPacket, zone: &Zone, diffs: Option<Vec<Diff>>, client_serial: u32, session: Option<&mut Session>) -> io::Result<()> {
+    if !serial_newer(zone.serial(), client_serial) {
+        debug!("IXFR client of zone {}. is up to date at serial {}", zone.origin, client_serial);
+        return send_records(stream, request, &[zone.soa()], session);
+    }
+
+    let Some(diffs) = diffs else {
+        info!("Journal of zone {}. doesn't reach back to serial {}, sending the whole zone", zone.origin, client_serial);
+        return send_axfr(stream, request, zone, session);
+    };
+
+    let mut records = vec![zone.soa()];
+    records.extend(diffs.iter().flat_map(Diff::records));
+    records.push(zone.soa());
+    send_records(stream, request, &records, session)?;
+
+    info!("Sent {} changes to zone {}. from serial {} to {} over IXFR", diffs.len(), zone.origin, client_serial, zone.serial());
+    return Ok(());
+}
+
+// Pulls the whole zone from the primary (RFC 5936), without the closing SOA
+pub fn request_axfr(primary: SocketAddr, origin: &str, key: Option<&Key>, timeout: Duration) -> Result<Vec<DnsRecord>, String> {
+    let mut reader  = TransferReader::start(primary, transfer_request(origin, AXFR, None), key, timeout)?;
+    let mut records = vec![reader.next_soa()?];
+
+    // The second SOA closes the transfer
+    loop {
+        let record = reader.next()?;
+        if record.get_qtype() == QueryType::SOA {
+            reader.finish()?;
+            return Ok(records);
+        }
+
+        records.push(record);
+    }
+}
+
+// Asks the primary for what changed since our copy of the zone (RFC 1995 4)
+pub fn request_ixfr(primary: SocketAddr, zone: &Zone, key: Option<&Key>, timeout: Duration) -> Result<Transfer, String> {
+    let request    = transfer_request(&zone.origin, IXFR, Some(zone.soa().clone()));
+    let mut reader = TransferReader::start(primary, request, key, timeout)?;
+
+    let latest = reader.next_soa()?;
+    let serial = soa_serial(&latest);
+    if !serial_newer(serial, zone.serial()) {
+        reader.finish()?;
+        return Ok(Transfer::UpToDate);
+    }
+
+    // Our own SOA next means a list of changes, anything else is the whole zone
+    let mut record = reader.next()?;
+    if record.get_qtype() != QueryType::SOA || soa_serial(&record) != zone.serial() {
+        let mut records = vec![latest];
+        while record.get_qtype() != QueryType::SOA {
+            records.push(record);
+            record = reader.next()?;
+        }
+
+        reader.finish()?;
+        return Ok(Transfer::Full(records));
+    }
+
+    // Each change is the old SOA, deletions, the new SOA and additions. The one that
+    // reaches the latest serial is followed by the closing SOA.
+    let mut diffs = Vec::new();
+    loop {
+        let mut diff = Diff { deleted: vec![record], added: Vec::new() };
+        loop {
+            let next = reader.next()?;
+            if next.get_qtype() == QueryType::SOA {
+                diff.added.push(next);
+                break;
+            }
+
+            diff.deleted.push(next);
+        }
+
+        record = reader.next()?;
+        while record.get_qtype() != QueryType::SOA {
+            diff.added.push(record);
+            record = reader.next()?;
+        }
+
+        let done = diff.new_serial() == serial;
+        diffs.push(diff);
+
+        if done {
+            reader.finish()?;
+            return Ok(Transfer::Incremental(diffs));
+        }
+    }
+}
+
+// Keeps a secondary zone in sync with its primary: checks the SOA serial every
+// refresh interval, transfers the zone when it changed, retries more often while
+// the primary can't be reached and stops serving the zone once it expires
+// (RFC 1034 4.3.5)
+pub fn run_secondary(zones: Arc<ZoneStore>, origin: String, primary: SocketAddr, notify: Vec<SocketAddr>, key: Option<Key>, config: ResolverConfig) {
+    let requests         = zones.refresh_requests(&origin);
+    let mut last_refresh = Instant::now();
+
+    loop {
+        let current = zones.get(&origin);
+
+        let wait = match refresh(&zones, &origin, primary, current.as_deref(), key.as_ref(), &config) {
+            Ok(()) => {
+                last_refresh = Instant::now();
+
+                // Pass a new serial on to secondaries of our own
+                let updated = zones.get(&origin);
+                if let Some(ref zone) = updated {
+                    if current.as_ref().is_none_or(|current| current.serial() != zone.serial()) {
+                        notify::send_notify(zone, &notify, key.as_ref(), config.timeout);
+                    }
+                }
+
+                timers(updated.as_deref()).map_or(INITIAL_RETRY, |(refresh, _, _)| refresh)
+            },
+            Err(err) => {
+                warn!("Refreshing zone {}. from {} failed: {}", origin, primary, err);
+
+                match timers(current.as_deref()) {
+                    Some((_, retry, expire)) => {
+                        if last_refresh.elapsed() >= expire {
+                            warn!("Zone {}. expired, answering SERVFAIL until the next transfer", origin);
+                            zones.expire(&origin);
+                        }
+
+                        retry
+                    },
+                    None => INITIAL_RETRY,
+                }
+            },
+        };
+
+        // A NOTIFY from the primary cuts the wait short (RFC 1996 4.7)
+        if requests.recv_timeout(wait).is_ok() {
+            while requests.try_recv().is_ok() {}
+            info!("Refreshing zone {}. on NOTIFY", origin);
+        }
+    }
+}
+
+fn refresh(zones: &ZoneStore, origin: &str, primary: SocketAddr, current: Option<&Zone>, key: Option<&Key>, config: &ResolverConfig) -> Result<(), String> {
+    // A transfer is only worth it when the primary has a newer serial
+    if let Some(zone) = current {
+        let response = lookup((primary.ip(), primary.port()), origin, QueryType::SOA, false, config)
+                           .map_err(|_| "no reply to the SOA query".to_string())?;
+
+        let serial = response.answer_section.iter().find_map(|record| match *record {
+            DnsRecord::SOA { serial, .. } => Some(serial),
+            _                             => None,
+        });
+
+        match serial {
+            Some(serial) if !serial_newer(serial, zone.serial()) => {
+                debug!("Zone {}. is up to date at serial {}", origin, zone.serial());
+                return Ok(());
+            },
+            Some(_) => (),
+            None    => return Err(format!("no SOA in the reply, {:?}", response.header.response_code)),
+        }
+    }
+
+    // IXFR first when we have something to start from, AXFR when that fails
+    let incremental = match current {
+        Some(zone) => match request_ixfr(primary, zone, key, config.timeout) {
+            Ok(Transfer::UpToDate)            => return Ok(()),
+            Ok(Transfer::Incremental(diffs)) => zone.apply(&diffs).map(|zone| Some((zone, "IXFR"))),
+            Ok(Transfer::Full(records))      => Zone::from_records(origin, records).map(|zone| Some((zone, "IXFR, whole zone"))),
+            Err(err)                          => Err(err),
+        },
+        None => Ok(None),
+    };
+
+    let (zone, method) = match incremental {
+        Ok(Some(result)) => result,
+        Ok(None)         => (Zone::from_records(origin, request_axfr(primary, origin, key, config.timeout)?)?, "AXFR"),
+        Err(err)         => {
+            info!("IXFR of zone {}. from {} failed, falling back to AXFR: {}", origin, primary, err);
+            (Zone::from_records(origin, request_axfr(primary, origin, key, config.timeout)?)?, "AXFR")
+        },
+    };
+
+    info!("Transferred zone {}. serial {} with {} records from {} over {}", origin, zone.serial(), zone.record_count(), primary, method);
+    zones.commit(zone);
+
+    return Ok(());
+}
+
+// Refresh, retry and expire intervals from the zone's SOA
+fn timers(zone: Option<&Zone>) -> Option<(Duration, Duration, Duration)> {
+    match *zone?.soa() {
+        DnsRecord::SOA { refresh, retry, expire, .. } => Some((
+            Duration::from_secs(refresh as u64),
+            Duration::from_secs(retry as u64),
+            Duration::from_secs(expire as u64),
+        )),
+        _ => None,
+    }
+}
+
+// Query for a transfer. IXFR carries the SOA of the copy we have in the authority
+// section.
+fn transfer_request(origin: &str, qtype: QueryType, soa: Option<DnsRecord>) -> DnsPacket {
+    let mut request                  = DnsPacket::new();
+    request.header.packet_identifier = rand::random();
+    request.question_section.push(DnsQuestion::new(origin.to_string(), qtype, DnsClass::IN));
+    request.authority_section.extend(soa);
+
+    return request;
+}
+
+// Sends the records in as many messages as it takes. Only the first message repeats
+// the question.
+fn send_records(stream: &mut TcpStream, request: &DnsPacket, records: &[&DnsRecord], mut session: Option<&mut Session>) -> io::Result<()> {
+    let mut message = start_message(request, true);
+    let mut size    = 0;
+    for record in records {
+        let record_size = wire_size(record);
+        if size + record_size > MESSAGE_SIZE && !message.answer_section.is_empty() {
+            write_packet(stream, &mut message, session.as_deref_mut())?;
+            message = start_message(request, false);
+            size    = 0;
+        }
+
+        message.answer_section.push((*record).clone());
+        size += record_size;
+    }
+
+    return write_packet(stream, &mut message, session);
+}
+
+fn start_message(request: &DnsPacket, with_question: bool) -> DnsPacket {
+    let mut message                     = DnsPacket::new();
+    message.header.packet_identifier    = request.header.packet_identifier;
+    message.header.query_response       = true;
+    message.header.authoritative_answer = true;
+
+    if with_question {
+        message.question_section = request.question_section.clone();
+    }
+
+    return message;
+}
+
+// Every message of a signed transfer gets signed, rather than only every so often
+fn write_packet(stream: &mut TcpStream, message: &mut DnsPacket, session: Option<&mut Session>) -> io::Result<()> {
+    let mut buffer = PacketBuffer::with_limit(MAX_PACKET_SIZE);
+    message.write_packet_to_buffer(&mut buffer)
+           .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+
+    match session {
+        Some(session) => return dns_tcp::write_message(stream, &session.sign(buffer.get_data())),
+        None          => return dns_tcp::write_message(stream, buffer.get_data()),
+    }
+}
+
+// Size of the record on its own, which compression can only make smaller
+fn wire_size(record: &DnsRecord) -> usize {
+    let mut buffer = PacketBuffer::with_limit(MAX_PACKET_SIZE);
+    let _          = record.write(&mut buffer);
+    return buffer.get_pos();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    // A small zone at the serial with the records added, from a master file of its own
+    fn version(test: &str, serial: u32, records: &str) -> Zone {
+        let path = std::env::temp_dir().join(format!("transfer_{}_{}_{}", test, serial, std::process::id()));
+        fs::write(&path, format!("$TTL 300\n@ SOA ns1 hostmaster {} 3600 600 86400 60\n@ NS ns1\n{}", serial, records)).unwrap();
+
+        let zone = Zone::load("example.com", &path);
+        fs::remove_file(&path).unwrap();
+
+        return zone.unwrap();
+    }
+
+    fn records(zone: &Zone) -> Vec<String> {
+        return zone.records().iter().map(|record| record.to_string()).collect();
+    }
+
+    // Serves one IXFR of `zone` with `diffs` and requests it for `client`
+    fn transfer(zone: Zone, diffs: Option<Vec<Diff>>, client: &Zone) -> Transfer {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let primary  = listener.local_addr().unwrap();
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer      = dns_tcp::read_message(&mut stream).unwrap().unwrap();
+            let request         = DnsPacket::get_packet_from_buffer(&mut buffer).unwrap();
+
+            assert_eq!(request.question_section[0].qtype, IXFR);
+            let serial = request.authority_section.first().map_or(0, soa_serial);
+            send_ixfr(&mut stream, &request, &zone, diffs, serial, None).unwrap();
+        });
+
+        let result = request_ixfr(primary, client, None, Duration::from_secs(5));
+        server.join().unwrap();
+
+        return result.unwrap();
+    }
+
+    #[test]
+    fn ixfr_sends_the_changes() {
+        // Enough records that the changes take several messages
+        let many: String = (0..2000).map(|n| format!("host{} A 192.0.2.1\n", n)).collect();
+        let zones = [version("ixfr", 1, "www A 192.0.2.1\n"), version("ixfr", 2, "www A 192.0.2.2\n"), version("ixfr", 3, &many)];
+        let diffs = vec![Diff::between(&zones[0], &zones[1]), Diff::between(&zones[1], &zones[2])];
+
+        let Transfer::Incremental(received) = transfer(zones[2].clone(), Some(diffs.clone()), &zones[0]) else {
+            panic!("expected the changes");
+        };
+
+        let serials: Vec<(u32, u32)> = received.iter().map(|diff| (diff.old_serial(), diff.new_serial())).collect();
+        assert_eq!(serials, [(1, 2), (2, 3)]);
+        assert_eq!(received[1].added.len(), 2001);
+        assert_eq!(records(&zones[0].apply(&received).unwrap()), records(&zones[2]));
+    }
+
+    #[test]
+    fn ixfr_falls_back_to_the_whole_zone() {
+        let old = version("full", 1, "www A 192.0.2.1\n");
+        let new = version("full", 2, "www A 192.0.2.2\nftp A 192.0.2.3\n");
+
+        let Transfer::Full(received) = transfer(new.clone(), None, &old) else {
+            panic!("expected the whole zone");
+        };
+
+        let received: Vec<String> = received.iter().map(|record| record.to_string()).collect();
+        assert_eq!(received, records(&new));
+    }
+
+    #[test]
+    fn ixfr_up_to_date() {
+        let zone = version("current", 5, "");
+        assert!(matches!(transfer(zone.clone(), Some(Vec::new()), &zone), Transfer::UpToDate));
+    }
+}